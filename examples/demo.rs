@@ -2,7 +2,7 @@
 
 use rmqttc::{
     Config, ConnectProperties, MqttClient, MqttResult, MqttRouter, Params, Payload, QoS,
-    StateHandle, types,
+    ServeOptions, StateHandle, Utf8Lossy, types,
 };
 use serde::Deserialize;
 use std::time::Duration;
@@ -101,7 +101,7 @@ async fn mqtt_event_err_msg(
     Ok(())
 }
 async fn mqtt_unkonw_msg(
-    Payload(evt): Payload<String>,
+    Utf8Lossy(evt): Utf8Lossy,
     Params(_): Params<serde_json::Value>,
     StateHandle(_): StateHandle<InstanceHandle>,
 ) -> MqttResult {
@@ -142,7 +142,7 @@ async fn main() {
     opts.set_clean_start(false);
     opts.set_credentials("test", "GS+g==");
 
-    let (tx, mut rx) = mpsc::channel(64);
+    let (tx, rx) = mpsc::channel(64);
     let cli = match rmqttc::start_with_cfg(opts, Duration::from_secs(10), tx).await {
         Ok(cli) => cli,
         Err(e) => {
@@ -196,15 +196,7 @@ async fn main() {
         .add(types::UnkonwTopic, mqtt_unkonw_msg)
         .expect("route error");
 
-    tokio::spawn(async move {
-        loop {
-            while let Some(msg) = rx.recv().await {
-                if let Err(e) = router.dispatch(msg, state.clone()).await {
-                    log::error!("dispatch error: {}", e);
-                }
-            }
-        }
-    });
+    router.serve(rx, state, ServeOptions::default());
 
     log::info!("mqtt state: {}", cli.state());
     log::info!("----------wait for ctrl-c signal----------");
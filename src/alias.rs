@@ -0,0 +1,213 @@
+use crate::{Message, QoS};
+use bytes::Bytes;
+use rumqttc::v5::{EventLoop, Request};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// topic alias 属性占用的字节数：1 字节标识 + 2 字节别名
+const ALIAS_PROPERTY_LEN: i64 = 3;
+
+/// 发布方向的主题别名表，连接成功后按 ConnAck 中的 topic_alias_maximum 重置，
+/// 断开后清空
+#[derive(Debug, Default)]
+pub(crate) struct OutgoingAliases {
+    inner: Mutex<OutgoingTable>,
+}
+
+#[derive(Debug, Default)]
+struct OutgoingTable {
+    max: u16,
+    topics: HashMap<String, u16>,
+    hits: u64,
+    bytes_saved: i64,
+}
+
+impl OutgoingAliases {
+    pub(crate) fn reset(&self, max: u16) {
+        let mut table = self.inner.lock().unwrap();
+        table.max = max;
+        table.topics.clear();
+    }
+
+    /// 使用别名的报文都在持有锁时调用 `enqueue(主题, 别名)` 入队，入队成功返回 `None`：
+    /// 建立映射的报文一定先于只带别名的报文进入发送队列，
+    /// 也不会在 [`OutgoingAliases::invalidate`] 之后才入队。
+    /// 不使用别名或入队失败时返回需要由调用方发送的主题与别名。
+    ///
+    /// 只对 QoS0 使用别名：QoS1/2 的报文在重连后会原样重发，而新连接上别名映射已经失效
+    pub(crate) fn apply(
+        &self,
+        topic: String,
+        qos: QoS,
+        mut enqueue: impl FnMut(&str, u16) -> bool,
+    ) -> Option<(String, Option<u16>)> {
+        if qos != QoS::AtMostOnce || topic.is_empty() {
+            return Some((topic, None));
+        }
+        let mut table = self.inner.lock().unwrap();
+        if let Some(&alias) = table.topics.get(&topic) {
+            // 发送队列已满时带上完整主题异步发送，映射已经建立
+            if !enqueue("", alias) {
+                return Some((topic, Some(alias)));
+            }
+            table.hits += 1;
+            table.bytes_saved += topic.len() as i64 - ALIAS_PROPERTY_LEN;
+            return None;
+        }
+        if table.topics.len() >= table.max as usize {
+            return Some((topic, None));
+        }
+        let alias = table.topics.len() as u16 + 1;
+        // 发送队列已满时不建立映射
+        if !enqueue(&topic, alias) {
+            return Some((topic, None));
+        }
+        table.topics.insert(topic, alias);
+        table.bytes_saved -= ALIAS_PROPERTY_LEN;
+        None
+    }
+
+    /// 连接断开时调用：清空别名表，还没发出的报文去掉别名并补回完整主题，
+    /// 避免重连后在新连接上使用失效的别名
+    pub(crate) fn invalidate(&self, eventloop: &mut EventLoop) {
+        let topics = {
+            let mut table = self.inner.lock().unwrap();
+            table.max = 0;
+            std::mem::take(&mut table.topics)
+        };
+        // 清空之后不会再有别名报文入队，clean 把发送通道里剩下的请求移到 pending
+        eventloop.clean();
+        let topics: HashMap<u16, String> = topics.into_iter().map(|(t, a)| (a, t)).collect();
+        for request in eventloop.pending.iter_mut() {
+            let Request::Publish(publish) = request else {
+                continue;
+            };
+            let Some(properties) = publish.properties.as_mut() else {
+                continue;
+            };
+            let Some(topic) = properties.topic_alias.and_then(|a| topics.get(&a)) else {
+                continue;
+            };
+            if publish.topic.is_empty() {
+                publish.topic = Bytes::from(topic.clone());
+            } else if publish.topic != topic.as_bytes() {
+                // 调用方自己设置的别名
+                continue;
+            }
+            properties.topic_alias = None;
+        }
+    }
+
+    /// (当前别名数, 使用别名发送的次数, 节省的字节数)
+    pub(crate) fn stats(&self) -> (usize, u64, i64) {
+        let table = self.inner.lock().unwrap();
+        (table.topics.len(), table.hits, table.bytes_saved)
+    }
+}
+
+/// 接收方向的主题别名表，只在一次连接内有效，连接和断开时清空
+#[derive(Debug, Default)]
+pub(crate) struct IncomingAliases {
+    topics: HashMap<u16, Bytes>,
+}
+
+impl IncomingAliases {
+    pub(crate) fn clear(&mut self) {
+        self.topics.clear();
+    }
+
+    /// 带主题的消息建立或更新别名映射，空主题的消息替换为别名对应的主题，
+    /// 别名未建立时返回 false
+    pub(crate) fn resolve(&mut self, msg: &mut Message) -> bool {
+        let Some(alias) = msg.properties.as_ref().and_then(|p| p.topic_alias) else {
+            return true;
+        };
+        if !msg.topic.is_empty() {
+            self.topics.insert(alias, msg.topic.clone());
+            return true;
+        }
+        match self.topics.get(&alias) {
+            Some(topic) => {
+                msg.topic = topic.clone();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rumqttc::v5::mqttbytes::v5::PublishProperties;
+
+    #[test]
+    fn outgoing_alias_assign_and_reset() {
+        let aliases = OutgoingAliases::default();
+        let topic = "/sub/request/tst/00ab1bd0719e0c3f4b0ec92c261cf102".to_string();
+        let sent = Mutex::new(Vec::new());
+        let apply = |topic: &str, qos| {
+            aliases.apply(topic.into(), qos, |topic, alias| {
+                sent.lock().unwrap().push((topic.to_string(), alias));
+                true
+            })
+        };
+        // broker 不支持别名
+        assert_eq!(apply(&topic, QoS::AtMostOnce), Some((topic.clone(), None)));
+
+        aliases.reset(1);
+        assert_eq!(apply(&topic, QoS::AtMostOnce), None);
+        assert_eq!(apply(&topic, QoS::AtMostOnce), None);
+        assert_eq!(
+            *sent.lock().unwrap(),
+            [(topic.clone(), 1), (String::new(), 1)]
+        );
+        assert_eq!(apply(&topic, QoS::AtLeastOnce), Some((topic.clone(), None)));
+        // 超出 broker 允许的数量
+        assert_eq!(apply("a/b", QoS::AtMostOnce), Some(("a/b".into(), None)));
+        // 入队失败时带上完整主题，新主题不建立映射
+        assert_eq!(
+            aliases.apply(topic.clone(), QoS::AtMostOnce, |_, _| false),
+            Some((topic.clone(), Some(1)))
+        );
+
+        let saved = topic.len() as i64 - 2 * ALIAS_PROPERTY_LEN;
+        assert_eq!(aliases.stats(), (1, 1, saved));
+
+        aliases.reset(10);
+        assert_eq!(aliases.stats().0, 0);
+        assert_eq!(
+            aliases.apply(topic.clone(), QoS::AtMostOnce, |_, _| false),
+            Some((topic.clone(), None))
+        );
+        assert_eq!(aliases.stats().0, 0);
+    }
+
+    fn aliased(topic: &str, alias: u16) -> Message {
+        let properties = PublishProperties {
+            topic_alias: Some(alias),
+            ..PublishProperties::default()
+        };
+        Message::new(topic, QoS::AtMostOnce, "1", Some(properties))
+    }
+
+    #[test]
+    fn incoming_alias_resolve_and_clear() {
+        let mut aliases = IncomingAliases::default();
+        let mut msg = aliased("", 1);
+        assert!(!aliases.resolve(&mut msg));
+
+        assert!(aliases.resolve(&mut aliased("dev/1/temp", 1)));
+        assert!(aliases.resolve(&mut msg));
+        assert_eq!(msg.topic, "dev/1/temp");
+
+        // 同一别名可以重新映射到其他主题
+        assert!(aliases.resolve(&mut aliased("dev/2/temp", 1)));
+        let mut msg = aliased("", 1);
+        assert!(aliases.resolve(&mut msg));
+        assert_eq!(msg.topic, "dev/2/temp");
+
+        aliases.clear();
+        assert!(!aliases.resolve(&mut aliased("", 1)));
+    }
+}
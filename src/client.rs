@@ -1,9 +1,15 @@
-use crate::{MqttResult, PublishMessage, QoS, State};
+use crate::{
+    Codec, Compression, Json, Message, MqttResult, OutgoingAliases, PublishMessage, QoS, Rpc,
+    State, validate_topic_filter, validate_topic_name,
+};
 use anyhow::anyhow;
 use bytes::Bytes;
 use rumqttc::v5::AsyncClient;
+use rumqttc::v5::mqttbytes::v5::PublishProperties;
+use serde::Serialize;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::{
     select,
     sync::{Mutex, watch},
@@ -33,12 +39,28 @@ impl Topics {
     }
 }
 
+/// 客户端统计信息，`topic_aliases` 只统计当前连接，其余为累计值
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClientStats {
+    /// 当前连接已分配的主题别名数
+    pub topic_aliases: usize,
+    /// 只发送别名的发布次数
+    pub alias_hits: u64,
+    /// 使用别名节省的字节数（已扣除别名属性本身的开销）
+    pub alias_bytes_saved: i64,
+}
+
 #[derive(Debug)]
 pub struct Client {
     state: watch::Receiver<State>,
     mqtt: AsyncClient,
     close: watch::Sender<bool>,
     topics: Mutex<Topics>,
+    // 与 Manager 共享，Client::new 创建的客户端没有
+    rpc: Option<Arc<Rpc>>,
+    aliases: Arc<OutgoingAliases>,
+    // 串行化回复主题的订阅
+    reply_sub: Mutex<()>,
 }
 
 impl Drop for Client {
@@ -48,10 +70,32 @@ impl Drop for Client {
 }
 
 impl Client {
+    /// 不经过 [`crate::start_with_cfg`] 创建的客户端收不到回复，不能使用 [`Client::request`]，
+    /// 也不使用主题别名
     pub fn new(
         state: watch::Receiver<State>,
         mqtt: AsyncClient,
         close: watch::Sender<bool>,
+    ) -> Self {
+        Self::build(state, mqtt, close, None, Arc::default())
+    }
+
+    pub(crate) fn with_shared(
+        state: watch::Receiver<State>,
+        mqtt: AsyncClient,
+        close: watch::Sender<bool>,
+        rpc: Arc<Rpc>,
+        aliases: Arc<OutgoingAliases>,
+    ) -> Self {
+        Self::build(state, mqtt, close, Some(rpc), aliases)
+    }
+
+    fn build(
+        state: watch::Receiver<State>,
+        mqtt: AsyncClient,
+        close: watch::Sender<bool>,
+        rpc: Option<Arc<Rpc>>,
+        aliases: Arc<OutgoingAliases>,
     ) -> Self {
         let topics = Mutex::new(Topics::new());
         Client {
@@ -59,6 +103,9 @@ impl Client {
             mqtt,
             close,
             topics,
+            rpc,
+            aliases,
+            reply_sub: Mutex::new(()),
         }
     }
 
@@ -73,6 +120,7 @@ impl Client {
     }
 
     pub async fn subscribe(&self, topic: &str, qos: crate::QoS) -> MqttResult {
+        validate_topic_filter(topic)?;
         if *self.state.borrow() != State::Connected {
             return Err(anyhow!("mqtt not connected"));
         }
@@ -83,6 +131,7 @@ impl Client {
     }
 
     pub async fn unsubscribe(&self, topic: &str) -> MqttResult {
+        validate_topic_filter(topic)?;
         if *self.state.borrow() != State::Connected {
             return Err(anyhow!("mqtt not connected"));
         }
@@ -151,26 +200,218 @@ impl Client {
         P: Into<Bytes>,
         S: Into<String>,
     {
+        self.send(topic.into(), payload.into(), qos, retain, None)
+            .await
+    }
+
+    pub async fn publish_with_properties<P, S>(
+        &self,
+        topic: S,
+        payload: P,
+        qos: crate::QoS,
+        retain: bool,
+        properties: PublishProperties,
+    ) -> MqttResult
+    where
+        P: Into<Bytes>,
+        S: Into<String>,
+    {
+        self.send(topic.into(), payload.into(), qos, retain, Some(properties))
+            .await
+    }
+
+    // 所有发布都经过这里，QoS0 的重复主题自动使用主题别名，
+    // 调用方已经设置了 topic_alias 时不做处理
+    async fn send(
+        &self,
+        topic: String,
+        payload: Bytes,
+        qos: crate::QoS,
+        retain: bool,
+        properties: Option<PublishProperties>,
+    ) -> MqttResult {
+        let user_alias = properties.as_ref().is_some_and(|p| p.topic_alias.is_some());
+        // 调用方自己管理别名时，空主题表示使用已建立的别名
+        if !(user_alias && topic.is_empty()) {
+            validate_topic_name(&topic)?;
+        }
         if *self.state.borrow() != State::Connected {
             return Err(anyhow!("mqtt not connected"));
         }
-        self.mqtt.publish(topic, qos, retain, payload).await?;
+        let (topic, alias) = if user_alias {
+            (topic, None)
+        } else {
+            let sent = self.aliases.apply(topic, qos, |topic, alias| {
+                let mut properties = properties.clone().unwrap_or_default();
+                properties.topic_alias = Some(alias);
+                self.mqtt
+                    .try_publish_with_properties(topic, qos, retain, payload.clone(), properties)
+                    .is_ok()
+            });
+            let Some(sent) = sent else {
+                return Ok(());
+            };
+            sent
+        };
+        match (alias, properties) {
+            (None, None) => self.mqtt.publish(topic, qos, retain, payload).await?,
+            (alias, properties) => {
+                let mut properties = properties.unwrap_or_default();
+                if alias.is_some() {
+                    properties.topic_alias = alias;
+                }
+                self.mqtt
+                    .publish_with_properties(topic, qos, retain, payload, properties)
+                    .await?
+            }
+        }
         Ok(())
     }
 
-    pub async fn publish_msg(&self, msg: PublishMessage) -> MqttResult {
-        if *self.state.borrow() != State::Connected {
-            return Err(anyhow!("mqtt not connected"));
+    /// 当前连接的统计信息
+    pub fn stats(&self) -> ClientStats {
+        let (topic_aliases, alias_hits, alias_bytes_saved) = self.aliases.stats();
+        ClientStats {
+            topic_aliases,
+            alias_hits,
+            alias_bytes_saved,
         }
-        self.mqtt
-            .publish(
-                msg.topic,
-                msg.qos,
-                msg.retain,
-                crate::json_value_into_bytes(msg.data),
-            )
+    }
+
+    /// 以 JSON 序列化后发布，并设置 content type 为 `application/json`
+    pub async fn publish_json<T, S>(
+        &self,
+        topic: S,
+        payload: Json<T>,
+        qos: crate::QoS,
+        retain: bool,
+    ) -> MqttResult
+    where
+        T: Serialize,
+        S: Into<String>,
+    {
+        let properties = PublishProperties {
+            payload_format_indicator: Some(1),
+            content_type: Some("application/json".to_string()),
+            ..PublishProperties::default()
+        };
+        self.publish_with_properties(topic, payload.to_bytes()?, qos, retain, properties)
+            .await
+    }
+
+    /// 用 `codec` 编码后发布，并设置对应的 content type，
+    /// 例如 `client.publish_encoded(topic, &Format::Cbor, &reading, QoS::AtLeastOnce, false)`
+    pub async fn publish_encoded<C, T, S>(
+        &self,
+        topic: S,
+        codec: &C,
+        value: &T,
+        qos: crate::QoS,
+        retain: bool,
+    ) -> MqttResult
+    where
+        C: Codec<T>,
+        S: Into<String>,
+    {
+        let content_type = codec.content_type();
+        let properties = PublishProperties {
+            payload_format_indicator: (content_type == crate::JSON_CONTENT_TYPE).then_some(1),
+            content_type: Some(content_type.to_string()),
+            ..PublishProperties::default()
+        };
+        self.publish_with_properties(topic, codec.encode(value)?, qos, retain, properties)
+            .await
+    }
+
+    /// 压缩后发布，并通过用户属性 [`crate::CONTENT_ENCODING`] 标记压缩方式，
+    /// 使用本库的接收方在派发前自动解压
+    pub async fn publish_compressed<P, S>(
+        &self,
+        topic: S,
+        payload: P,
+        compression: Compression,
+        qos: crate::QoS,
+        retain: bool,
+    ) -> MqttResult
+    where
+        P: AsRef<[u8]>,
+        S: Into<String>,
+    {
+        let properties = PublishProperties {
+            user_properties: vec![(
+                crate::CONTENT_ENCODING.to_string(),
+                compression.name().to_string(),
+            )],
+            ..PublishProperties::default()
+        };
+        let payload = compression.compress(payload.as_ref())?;
+        self.publish_with_properties(topic, payload, qos, retain, properties)
+            .await
+    }
+
+    /// 发送请求并等待回复：设置 response topic 与唯一的 correlation data，
+    /// 返回 correlation data 匹配的回复消息，超过 `timeout` 未收到回复时返回错误。
+    ///
+    /// 回复主题由 ConnAck 中的 response information 生成（需要在连接属性中设置
+    /// `request_response_info`），broker 未提供时使用 `rmqttc/reply/{client_id}`
+    pub async fn request<P, S>(
+        &self,
+        topic: S,
+        payload: P,
+        timeout: Duration,
+    ) -> MqttResult<Message>
+    where
+        P: Into<Bytes>,
+        S: Into<String>,
+    {
+        // 回复由 Manager 按 correlation data 派发，没有 Manager 时永远等不到回复
+        let Some(rpc) = self.rpc.as_deref() else {
+            return Err(anyhow!(
+                "mqtt request needs a client created by start_with_cfg"
+            ));
+        };
+        let reply_topic = self.ensure_reply_subscription(rpc).await?;
+        let mut pending = rpc.register();
+        let properties = PublishProperties {
+            response_topic: Some(reply_topic),
+            correlation_data: Some(pending.correlation.clone()),
+            ..PublishProperties::default()
+        };
+        self.publish_with_properties(topic, payload, QoS::AtLeastOnce, false, properties)
             .await?;
-        Ok(())
+        match tokio::time::timeout(timeout, &mut pending.rx).await {
+            Ok(Ok(msg)) => Ok(msg),
+            Ok(Err(_)) => Err(anyhow!("mqtt request canceled")),
+            Err(_) => Err(anyhow!("mqtt request timeout after {:?}", timeout)),
+        }
+    }
+
+    // 回复主题变化（例如重连后 broker 给出了不同的 response information）时重新订阅
+    async fn ensure_reply_subscription(&self, rpc: &Rpc) -> MqttResult<String> {
+        let _guard = self.reply_sub.lock().await;
+        let topic = rpc.reply_topic();
+        let subscribed = rpc.subscribed();
+        if subscribed.as_deref() == Some(topic.as_str()) {
+            return Ok(topic);
+        }
+        if let Some(old) = subscribed
+            && let Err(e) = self.unsubscribe(&old).await
+        {
+            log::warn!("unsubscribe reply topic {} failed: {}", old, e);
+        }
+        self.subscribe(&topic, QoS::AtLeastOnce).await?;
+        rpc.set_subscribed(Some(topic.clone()));
+        Ok(topic)
+    }
+
+    pub async fn publish_msg(&self, msg: PublishMessage) -> MqttResult {
+        self.publish(
+            msg.topic,
+            crate::json_value_into_bytes(msg.data),
+            msg.qos,
+            msg.retain,
+        )
+        .await
     }
 
     pub async fn close(&self) -> MqttResult {
@@ -184,3 +425,225 @@ impl Client {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::router::test::{sent, shared_client_with_loop, test_client_with_loop};
+
+    #[tokio::test]
+    async fn request_timeout_clears_pending() -> MqttResult {
+        let rpc = Arc::new(Rpc::new("dev1"));
+        let (client, mut eventloop) = shared_client_with_loop(rpc.clone(), Arc::default(), 10);
+
+        for _ in 0..2 {
+            let err = client
+                .request("svc/ping", "ping", Duration::from_millis(10))
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains("timeout"));
+        }
+        assert_eq!(rpc.pending_len(), 0);
+
+        eventloop.clean();
+        let mut subscribes = 0;
+        let mut correlations = Vec::new();
+        for req in eventloop.pending.drain(..) {
+            match req {
+                rumqttc::v5::Request::Subscribe(sub) => {
+                    subscribes += 1;
+                    assert_eq!(sub.filters[0].path, "rmqttc/reply/dev1");
+                }
+                rumqttc::v5::Request::Publish(msg) => {
+                    let props = msg.properties.unwrap();
+                    assert_eq!(props.response_topic.as_deref(), Some("rmqttc/reply/dev1"));
+                    correlations.push(props.correlation_data.unwrap());
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(subscribes, 1);
+        assert_eq!(correlations.len(), 2);
+        assert_ne!(correlations[0], correlations[1]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn publish_with_caller_alias() -> MqttResult {
+        let (client, mut eventloop) = test_client_with_loop();
+
+        let aliased = || PublishProperties {
+            topic_alias: Some(7),
+            ..PublishProperties::default()
+        };
+        client
+            .publish_with_properties("dev/1/temp", "1", QoS::AtMostOnce, false, aliased())
+            .await?;
+        client
+            .publish_with_properties("", "2", QoS::AtMostOnce, false, aliased())
+            .await?;
+        // 没有别名时空主题仍然不合法，带别名的主题也不能包含通配符
+        assert!(
+            client
+                .publish("", "3", QoS::AtMostOnce, false)
+                .await
+                .is_err()
+        );
+        assert!(
+            client
+                .publish_with_properties("dev/+", "4", QoS::AtMostOnce, false, aliased())
+                .await
+                .is_err()
+        );
+
+        let sent = sent(&mut eventloop);
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[1].topic, "");
+        assert_eq!(sent[1].properties.as_ref().unwrap().topic_alias, Some(7));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn publish_reuses_topic_alias() -> MqttResult {
+        let aliases = Arc::new(OutgoingAliases::default());
+        aliases.reset(4);
+        let (client, mut eventloop) =
+            shared_client_with_loop(Arc::new(Rpc::new("test")), aliases, 10);
+
+        let topic = "/sub/request/tst/00ab1bd0719e0c3f4b0ec92c261cf102";
+        for _ in 0..3 {
+            client.publish(topic, "1", QoS::AtMostOnce, false).await?;
+        }
+        client.publish(topic, "1", QoS::AtLeastOnce, false).await?;
+
+        let sent = sent(&mut eventloop);
+        let alias = |msg: &Message| msg.properties.as_ref().and_then(|p| p.topic_alias);
+        assert_eq!(sent[0].topic, topic);
+        assert_eq!(alias(&sent[0]), Some(1));
+        for msg in &sent[1..3] {
+            assert!(msg.topic.is_empty());
+            assert_eq!(alias(msg), Some(1));
+        }
+        assert_eq!(sent[3].topic, topic);
+        assert_eq!(alias(&sent[3]), None);
+
+        let stats = client.stats();
+        assert_eq!(stats.topic_aliases, 1);
+        assert_eq!(stats.alias_hits, 2);
+        assert_eq!(stats.alias_bytes_saved, 2 * topic.len() as i64 - 9);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn concurrent_publish_defines_alias_first() -> MqttResult {
+        let aliases = Arc::new(OutgoingAliases::default());
+        aliases.reset(4);
+        let (client, mut eventloop) =
+            shared_client_with_loop(Arc::new(Rpc::new("test")), aliases, 500);
+
+        let topic = "/sub/request/tst/00ab1bd0719e0c3f4b0ec92c261cf102";
+        let tasks: Vec<_> = (0..2)
+            .map(|_| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    for _ in 0..100 {
+                        client.publish(topic, "1", QoS::AtMostOnce, false).await?;
+                    }
+                    MqttResult::Ok(())
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await??;
+        }
+
+        let sent = sent(&mut eventloop);
+        assert_eq!(sent.len(), 200);
+        // 第一个报文建立映射，之后都只带别名
+        assert_eq!(sent[0].topic, topic);
+        for msg in &sent[1..] {
+            assert!(msg.topic.is_empty());
+        }
+        assert!(
+            sent.iter()
+                .all(|m| m.properties.as_ref().and_then(|p| p.topic_alias) == Some(1))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reconnect_drops_stale_aliases() -> MqttResult {
+        let aliases = Arc::new(OutgoingAliases::default());
+        aliases.reset(4);
+        let (client, mut eventloop) =
+            shared_client_with_loop(Arc::new(Rpc::new("test")), aliases.clone(), 10);
+
+        let topic = "/sub/request/tst/00ab1bd0719e0c3f4b0ec92c261cf102";
+        for _ in 0..3 {
+            client.publish(topic, "1", QoS::AtMostOnce, false).await?;
+        }
+        let caller_alias = PublishProperties {
+            topic_alias: Some(9),
+            ..PublishProperties::default()
+        };
+        client
+            .publish_with_properties("", "2", QoS::AtMostOnce, false, caller_alias)
+            .await?;
+
+        // 断开时还在队列中的报文重连后发送，不能再带旧连接的别名
+        aliases.invalidate(&mut eventloop);
+        let alias = |msg: &Message| msg.properties.as_ref().and_then(|p| p.topic_alias);
+        let queued = sent(&mut eventloop);
+        assert_eq!(queued.len(), 4);
+        for msg in &queued[..3] {
+            assert_eq!(msg.topic, topic);
+            assert_eq!(alias(msg), None);
+        }
+        assert_eq!(alias(&queued[3]), Some(9));
+        assert_eq!(client.stats().topic_aliases, 0);
+
+        // 新连接重新分配别名
+        aliases.reset(4);
+        client.publish(topic, "1", QoS::AtMostOnce, false).await?;
+        let sent = sent(&mut eventloop);
+        assert_eq!(sent[0].topic, topic);
+        assert_eq!(alias(&sent[0]), Some(1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_invalid_topics() {
+        let (client, _eventloop) = test_client_with_loop();
+
+        let err = client
+            .publish("a/+", "1", QoS::AtMostOnce, false)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<crate::TopicError>(),
+            Some(crate::TopicError::WildcardInName(_))
+        ));
+        let err = client
+            .subscribe("a/#/b", QoS::AtMostOnce)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<crate::TopicError>(),
+            Some(crate::TopicError::MisplacedMultiLevel(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn request_needs_shared_rpc() {
+        let (client, mut eventloop) = test_client_with_loop();
+
+        let err = client
+            .request("svc/echo", "1", Duration::from_millis(10))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("start_with_cfg"), "{err}");
+        // 没有订阅回复主题，也没有发出请求
+        eventloop.clean();
+        assert!(eventloop.pending.is_empty());
+    }
+}
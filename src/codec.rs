@@ -0,0 +1,412 @@
+use crate::{FromRequest, IntoResponse, MqttMessage, MqttResult, Request, Response};
+use bytes::Bytes;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::fmt::Display;
+use std::future::Future;
+use thiserror::Error;
+use tokio::task::futures::TaskLocalFuture;
+
+#[derive(Error, Debug)]
+pub enum CodecError {
+    #[error("unsupported content type {0}")]
+    UnsupportedContentType(String),
+    #[error("failed to encode {content_type} payload: {message}")]
+    Encode {
+        content_type: &'static str,
+        message: String,
+    },
+    #[error("failed to decode {content_type} payload on {topic}: {message}")]
+    Decode {
+        content_type: &'static str,
+        topic: String,
+        message: String,
+    },
+}
+
+/// payload 的编解码方式
+pub trait Codec<T> {
+    /// 发布时设置的 content type
+    fn content_type(&self) -> &'static str;
+    fn encode(&self, value: &T) -> Result<Bytes, CodecError>;
+    fn decode(&self, payload: &[u8]) -> Result<T, CodecError>;
+}
+
+fn encode_error(content_type: &'static str, e: impl Display) -> CodecError {
+    CodecError::Encode {
+        content_type,
+        message: e.to_string(),
+    }
+}
+
+// 解码错误在提取器中补上主题
+fn decode_error(content_type: &'static str, e: impl Display) -> CodecError {
+    CodecError::Decode {
+        content_type,
+        topic: String::new(),
+        message: e.to_string(),
+    }
+}
+
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+#[cfg(feature = "msgpack")]
+pub const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+#[cfg(feature = "cbor")]
+pub const CBOR_CONTENT_TYPE: &str = "application/cbor";
+#[cfg(feature = "protobuf")]
+pub const PROTOBUF_CONTENT_TYPE: &str = "application/x-protobuf";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct JsonCodec;
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for JsonCodec {
+    fn content_type(&self) -> &'static str {
+        JSON_CONTENT_TYPE
+    }
+
+    fn encode(&self, value: &T) -> Result<Bytes, CodecError> {
+        serde_json::to_vec(value)
+            .map(Bytes::from)
+            .map_err(|e| encode_error(JSON_CONTENT_TYPE, e))
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<T, CodecError> {
+        serde_json::from_slice(payload).map_err(|e| decode_error(JSON_CONTENT_TYPE, e))
+    }
+}
+
+/// MessagePack，结构体编码为 map，字段名随数据一起发送
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MsgPackCodec;
+
+#[cfg(feature = "msgpack")]
+impl<T: Serialize + DeserializeOwned> Codec<T> for MsgPackCodec {
+    fn content_type(&self) -> &'static str {
+        MSGPACK_CONTENT_TYPE
+    }
+
+    fn encode(&self, value: &T) -> Result<Bytes, CodecError> {
+        rmp_serde::to_vec_named(value)
+            .map(Bytes::from)
+            .map_err(|e| encode_error(MSGPACK_CONTENT_TYPE, e))
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<T, CodecError> {
+        rmp_serde::from_slice(payload).map_err(|e| decode_error(MSGPACK_CONTENT_TYPE, e))
+    }
+}
+
+#[cfg(feature = "cbor")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CborCodec;
+
+#[cfg(feature = "cbor")]
+impl<T: Serialize + DeserializeOwned> Codec<T> for CborCodec {
+    fn content_type(&self) -> &'static str {
+        CBOR_CONTENT_TYPE
+    }
+
+    fn encode(&self, value: &T) -> Result<Bytes, CodecError> {
+        let mut buf = Vec::new();
+        ciborium::into_writer(value, &mut buf).map_err(|e| encode_error(CBOR_CONTENT_TYPE, e))?;
+        Ok(Bytes::from(buf))
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<T, CodecError> {
+        ciborium::from_reader(payload).map_err(|e| decode_error(CBOR_CONTENT_TYPE, e))
+    }
+}
+
+/// Protobuf，`T` 为 prost 生成的消息类型
+#[cfg(feature = "protobuf")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProtobufCodec;
+
+#[cfg(feature = "protobuf")]
+impl<T: prost::Message + Default> Codec<T> for ProtobufCodec {
+    fn content_type(&self) -> &'static str {
+        PROTOBUF_CONTENT_TYPE
+    }
+
+    fn encode(&self, value: &T) -> Result<Bytes, CodecError> {
+        Ok(Bytes::from(value.encode_to_vec()))
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<T, CodecError> {
+        T::decode(payload).map_err(|e| decode_error(PROTOBUF_CONTENT_TYPE, e))
+    }
+}
+
+/// 基于 serde 的编码格式，可以按 content type 在运行时选择。
+/// Protobuf 不基于 serde，不在其中，需要使用 [`Protobuf`] 提取器
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Json,
+    #[cfg(feature = "msgpack")]
+    MsgPack,
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl Format {
+    /// 按 content type 选择格式，忽略 `;` 之后的参数与大小写，
+    /// `application/*+json` 也按 JSON 处理
+    pub fn from_content_type(content_type: &str) -> Result<Self, CodecError> {
+        match mime(content_type).as_str() {
+            "application/json" | "text/json" => Ok(Format::Json),
+            m if m.starts_with("application/") && m.ends_with("+json") => Ok(Format::Json),
+            #[cfg(feature = "msgpack")]
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Ok(Format::MsgPack)
+            }
+            #[cfg(feature = "cbor")]
+            "application/cbor" => Ok(Format::Cbor),
+            _ => Err(CodecError::UnsupportedContentType(content_type.to_string())),
+        }
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for Format {
+    fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json => JSON_CONTENT_TYPE,
+            #[cfg(feature = "msgpack")]
+            Format::MsgPack => MSGPACK_CONTENT_TYPE,
+            #[cfg(feature = "cbor")]
+            Format::Cbor => CBOR_CONTENT_TYPE,
+        }
+    }
+
+    fn encode(&self, value: &T) -> Result<Bytes, CodecError> {
+        match self {
+            Format::Json => JsonCodec.encode(value),
+            #[cfg(feature = "msgpack")]
+            Format::MsgPack => MsgPackCodec.encode(value),
+            #[cfg(feature = "cbor")]
+            Format::Cbor => CborCodec.encode(value),
+        }
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<T, CodecError> {
+        match self {
+            Format::Json => JsonCodec.decode(payload),
+            #[cfg(feature = "msgpack")]
+            Format::MsgPack => MsgPackCodec.decode(payload),
+            #[cfg(feature = "cbor")]
+            Format::Cbor => CborCodec.decode(payload),
+        }
+    }
+}
+
+// 去掉 `;` 之后的参数并转为小写
+fn mime(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+fn payload_of(message: &MqttMessage) -> Option<(&Bytes, Option<&str>)> {
+    match message {
+        MqttMessage::Msg(msg) => {
+            let content_type = msg
+                .properties
+                .as_ref()
+                .and_then(|p| p.content_type.as_deref());
+            Some((&msg.payload, content_type))
+        }
+        _ => None,
+    }
+}
+
+fn decode_request<S, T, C: Codec<T>>(req: &Request<S>, codec: &C) -> MqttResult<T> {
+    let Some((payload, _)) = payload_of(req.message()) else {
+        return Err(crate::RouterError::NotAPublish(req.message().to_string()).into());
+    };
+    codec.decode(payload).map_err(|e| match e {
+        CodecError::Decode {
+            content_type,
+            message,
+            ..
+        } => CodecError::Decode {
+            content_type,
+            topic: req.topic(),
+            message,
+        }
+        .into(),
+        e => e.into(),
+    })
+}
+
+tokio::task_local! {
+    // 路由执行 handler 期间为请求的编码格式
+    static REQUEST_FORMAT: Format;
+}
+
+// content type 优先，其次是路由设置的格式，默认为 JSON
+pub(crate) fn request_format<S>(req: &Request<S>) -> Result<Format, CodecError> {
+    match payload_of(req.message()).and_then(|(_, ct)| ct) {
+        Some(content_type) => Format::from_content_type(content_type),
+        None => Ok(req.format.unwrap_or_default()),
+    }
+}
+
+// 在 fut 执行期间记录请求的编码格式
+pub(crate) fn scope_format<F: Future>(format: Format, fut: F) -> TaskLocalFuture<Format, F> {
+    REQUEST_FORMAT.scope(format, fut)
+}
+
+/// 按消息的 content type 解码 payload；消息没有 content type 时使用路由设置的格式
+/// （[`crate::Dispatcher::with_format`]），都没有时按 JSON 解码。
+/// 作为返回值时按请求的格式编码，在路由之外使用时按 JSON 编码
+pub struct Encoded<T>(pub T);
+
+impl<S, T> FromRequest<S> for Encoded<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    type Rejection = anyhow::Error;
+
+    fn from_request(req: &Request<S>) -> MqttResult<Self> {
+        decode_request(req, &request_format(req)?).map(Encoded)
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> IntoResponse for Encoded<T> {
+    fn into_response(self) -> MqttResult<Response> {
+        let format = REQUEST_FORMAT.try_with(|f| *f).unwrap_or_default();
+        Response::encode(&format, &self.0)
+    }
+}
+
+/// 以 Protobuf 解码 payload，作为返回值时以 Protobuf 编码。
+/// 消息的 content type 不是 Protobuf 时拒绝请求，没有 content type 时直接解码
+#[cfg(feature = "protobuf")]
+pub struct Protobuf<T>(pub T);
+
+#[cfg(feature = "protobuf")]
+impl<S, T> FromRequest<S> for Protobuf<T>
+where
+    T: prost::Message + Default,
+{
+    type Rejection = anyhow::Error;
+
+    fn from_request(req: &Request<S>) -> MqttResult<Self> {
+        if let Some(content_type) = payload_of(req.message()).and_then(|(_, ct)| ct) {
+            match mime(content_type).as_str() {
+                PROTOBUF_CONTENT_TYPE | "application/protobuf" => {}
+                _ => return Err(CodecError::UnsupportedContentType(content_type.into()).into()),
+            }
+        }
+        decode_request(req, &ProtobufCodec).map(Protobuf)
+    }
+}
+
+#[cfg(feature = "protobuf")]
+impl<T: prost::Message + Default> IntoResponse for Protobuf<T> {
+    fn into_response(self) -> MqttResult<Response> {
+        Response::encode(&ProtobufCodec, &self.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Message, QoS};
+    use rumqttc::v5::mqttbytes::v5::PublishProperties;
+    use serde::Deserialize;
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Reading {
+        id: u32,
+        temp: f64,
+    }
+
+    fn request(payload: Bytes, content_type: Option<&str>) -> Request<()> {
+        let properties = PublishProperties {
+            content_type: content_type.map(str::to_string),
+            ..PublishProperties::default()
+        };
+        let msg = Message::new("dev/1/temp", QoS::AtMostOnce, payload, Some(properties));
+        Request::new(MqttMessage::Msg(msg), ())
+    }
+
+    #[test]
+    fn decode_by_content_type() -> MqttResult {
+        let reading = Reading { id: 1, temp: 21.5 };
+        #[allow(unused_mut)]
+        let mut formats = vec![Format::Json];
+        #[cfg(feature = "msgpack")]
+        formats.push(Format::MsgPack);
+        #[cfg(feature = "cbor")]
+        formats.push(Format::Cbor);
+
+        for format in formats {
+            let payload = format.encode(&reading)?;
+            let content_type = Codec::<Reading>::content_type(&format);
+            let Encoded(decoded) = Encoded::<Reading>::from_request(&request(
+                payload,
+                Some(&format!("{content_type}; charset=utf-8")),
+            ))?;
+            assert_eq!(decoded, reading);
+        }
+
+        let req = request(Bytes::from_static(b"{}"), Some("text/plain"));
+        let err = Encoded::<Reading>::from_request(&req).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<CodecError>(),
+            Some(CodecError::UnsupportedContentType(_))
+        ));
+        let req = request(Bytes::from_static(b"{"), None);
+        let err = Encoded::<Reading>::from_request(&req).err().unwrap();
+        assert!(err.to_string().contains("dev/1/temp"), "{err}");
+        Ok(())
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn route_format_without_content_type() -> MqttResult {
+        let reading = Reading { id: 2, temp: 3.0 };
+        let mut req = request(CborCodec.encode(&reading)?, None);
+        assert!(Encoded::<Reading>::from_request(&req).is_err());
+        req.format = Some(Format::Cbor);
+        assert_eq!(Encoded::<Reading>::from_request(&req)?.0, reading);
+        Ok(())
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct Ping {
+        #[prost(uint32, tag = "1")]
+        seq: u32,
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[test]
+    fn protobuf_round_trip() -> MqttResult {
+        let payload = ProtobufCodec.encode(&Ping { seq: 9 })?;
+        let req = request(payload, Some(PROTOBUF_CONTENT_TYPE));
+        let Protobuf(ping) = Protobuf::<Ping>::from_request(&req)?;
+        assert_eq!(ping.seq, 9);
+        let response = Protobuf(ping).into_response()?;
+        assert_eq!(
+            response.content_type.as_deref(),
+            Some(PROTOBUF_CONTENT_TYPE)
+        );
+
+        let payload = ProtobufCodec.encode(&Ping { seq: 3 })?;
+        let req = request(payload.clone(), None);
+        assert_eq!(Protobuf::<Ping>::from_request(&req)?.0.seq, 3);
+        let req = request(payload, Some(JSON_CONTENT_TYPE));
+        let err = Protobuf::<Ping>::from_request(&req).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<CodecError>(),
+            Some(CodecError::UnsupportedContentType(_))
+        ));
+        Ok(())
+    }
+}
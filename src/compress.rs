@@ -0,0 +1,237 @@
+use bytes::Bytes;
+use std::fmt::{self, Display};
+use thiserror::Error;
+
+/// 标记 payload 压缩方式的用户属性
+pub const CONTENT_ENCODING: &str = "content-encoding";
+
+/// 解压后 payload 的默认大小上限
+pub const DEFAULT_MAX_DECOMPRESSED: usize = 16 * 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum CompressError {
+    #[error("unsupported content encoding {0}")]
+    UnsupportedEncoding(String),
+    #[error("decompressed payload exceeds {limit} bytes")]
+    TooLarge { limit: usize },
+    #[error("{encoding} payload is corrupted: {source}")]
+    Corrupted {
+        encoding: Compression,
+        source: std::io::Error,
+    },
+    #[error("{encoding} compression failed: {source}")]
+    Compress {
+        encoding: Compression,
+        source: std::io::Error,
+    },
+}
+
+/// 发布时的压缩方式，通过用户属性 [`CONTENT_ENCODING`] 告知接收方
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    #[cfg(feature = "gzip")]
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+// 没有启用任何压缩 feature 时该类型没有取值
+#[cfg_attr(not(any(feature = "gzip", feature = "zstd")), allow(unused_variables))]
+impl Compression {
+    /// 用户属性中的取值
+    pub fn name(self) -> &'static str {
+        match self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => "gzip",
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self, CompressError> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            #[cfg(feature = "gzip")]
+            "gzip" => Ok(Compression::Gzip),
+            #[cfg(feature = "zstd")]
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(CompressError::UnsupportedEncoding(name.to_string())),
+        }
+    }
+
+    pub fn compress(self, payload: &[u8]) -> Result<Bytes, CompressError> {
+        match self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => {
+                use std::io::Write;
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder
+                    .write_all(payload)
+                    .map_err(|e| self.compress_failed(e))?;
+                encoder
+                    .finish()
+                    .map(Bytes::from)
+                    .map_err(|e| self.compress_failed(e))
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::encode_all(payload, 0)
+                .map(Bytes::from)
+                .map_err(|e| self.compress_failed(e)),
+        }
+    }
+
+    /// 解压，结果超过 `limit` 字节时停止并返回 [`CompressError::TooLarge`]
+    pub fn decompress(self, payload: &[u8], limit: usize) -> Result<Bytes, CompressError> {
+        match self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => self.read_limited(flate2::read::GzDecoder::new(payload), limit),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                let decoder =
+                    zstd::stream::read::Decoder::new(payload).map_err(|e| self.corrupted(e))?;
+                self.read_limited(decoder, limit)
+            }
+        }
+    }
+
+    // 最多读取 limit + 1 字节，避免解压炸弹占满内存
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    fn read_limited(
+        self,
+        reader: impl std::io::Read,
+        limit: usize,
+    ) -> Result<Bytes, CompressError> {
+        use std::io::Read;
+        let mut buf = Vec::new();
+        reader
+            .take(limit as u64 + 1)
+            .read_to_end(&mut buf)
+            .map_err(|e| self.corrupted(e))?;
+        if buf.len() > limit {
+            return Err(CompressError::TooLarge { limit });
+        }
+        Ok(Bytes::from(buf))
+    }
+
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    fn corrupted(self, source: std::io::Error) -> CompressError {
+        CompressError::Corrupted {
+            encoding: self,
+            source,
+        }
+    }
+
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    fn compress_failed(self, source: std::io::Error) -> CompressError {
+        CompressError::Compress {
+            encoding: self,
+            source,
+        }
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+// CONTENT_ENCODING 用户属性的位置
+fn encoding_position(msg: &crate::Message) -> Option<usize> {
+    msg.properties
+        .as_ref()?
+        .user_properties
+        .iter()
+        .position(|(k, _)| k.eq_ignore_ascii_case(CONTENT_ENCODING))
+}
+
+/// 消息是否带有 [`CONTENT_ENCODING`] 用户属性
+pub(crate) fn is_compressed(msg: &crate::Message) -> bool {
+    encoding_position(msg).is_some()
+}
+
+/// 按消息的 [`CONTENT_ENCODING`] 用户属性解压 payload，解压后移除该属性。
+/// 没有该属性时返回 `false`
+pub(crate) fn decompress_message(
+    msg: &mut crate::Message,
+    limit: usize,
+) -> Result<bool, CompressError> {
+    let Some(pos) = encoding_position(msg) else {
+        return Ok(false);
+    };
+    let Some(properties) = msg.properties.as_mut() else {
+        return Ok(false);
+    };
+    let encoding = Compression::from_name(&properties.user_properties[pos].1)?;
+    msg.payload = encoding.decompress(&msg.payload, limit)?;
+    properties.user_properties.remove(pos);
+    Ok(true)
+}
+
+#[cfg(all(test, any(feature = "gzip", feature = "zstd")))]
+mod test {
+    use super::*;
+    use crate::{Message, QoS};
+    use rumqttc::v5::mqttbytes::v5::PublishProperties;
+
+    fn compressions() -> Vec<Compression> {
+        [
+            #[cfg(feature = "gzip")]
+            Compression::Gzip,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd,
+        ]
+        .to_vec()
+    }
+
+    #[test]
+    fn round_trip_and_limit() -> Result<(), CompressError> {
+        let payload = "{\"level\":\"info\"}".repeat(1000);
+        for c in compressions() {
+            let compressed = c.compress(payload.as_bytes())?;
+            assert!(compressed.len() < payload.len());
+            assert_eq!(Compression::from_name(c.name())?, c);
+            assert_eq!(c.decompress(&compressed, payload.len())?, payload);
+            assert!(matches!(
+                c.decompress(&compressed, payload.len() - 1),
+                Err(CompressError::TooLarge { .. })
+            ));
+            assert!(matches!(
+                c.decompress(b"not compressed", 1024),
+                Err(CompressError::Corrupted { .. })
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn decompress_marked_message() -> Result<(), CompressError> {
+        let c = compressions()[0];
+        let properties = PublishProperties {
+            user_properties: vec![
+                (CONTENT_ENCODING.into(), c.name().into()),
+                ("trace".into(), "1".into()),
+            ],
+            ..PublishProperties::default()
+        };
+        let payload = c.compress(b"hello")?;
+        let mut msg = Message::new("logs", QoS::AtMostOnce, payload, Some(properties));
+        assert!(decompress_message(&mut msg, 1024)?);
+        assert_eq!(msg.payload, "hello");
+        let props = msg.properties.as_ref().unwrap();
+        assert_eq!(props.user_properties, [("trace".into(), "1".into())]);
+        // 属性已移除，不会重复解压
+        assert!(!decompress_message(&mut msg, 1024)?);
+
+        let properties = PublishProperties {
+            user_properties: vec![(CONTENT_ENCODING.into(), "br".into())],
+            ..PublishProperties::default()
+        };
+        let mut msg = Message::new("logs", QoS::AtMostOnce, "x", Some(properties));
+        assert!(matches!(
+            decompress_message(&mut msg, 1024),
+            Err(CompressError::UnsupportedEncoding(_))
+        ));
+        Ok(())
+    }
+}
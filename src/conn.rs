@@ -144,7 +144,7 @@ impl<const N: usize> Conn<N> {
                     log::trace!("[incoming]-ConnAck mqtt conn ack: {:?}", d);
                     match d.code {
                         ConnectReturnCode::Success => {
-                            return Some(MqttEventData::Connected);
+                            return Some(MqttEventData::Connected(d.properties));
                         }
                         _ => {
                             return Some(MqttEventData::Error(format!("[{:?}]", d.code)));
@@ -0,0 +1,158 @@
+use crate::{FromRequest, Message, MqttMessage, QoS, Request, RouterError};
+use bytes::Bytes;
+use rumqttc::v5::mqttbytes::v5::PublishProperties;
+use std::convert::Infallible;
+
+fn publish<S>(req: &Request<S>) -> Result<&Message, RouterError> {
+    match req.message() {
+        MqttMessage::Msg(msg) => Ok(msg),
+        evt => Err(RouterError::NotAPublish(evt.to_string())),
+    }
+}
+
+fn properties<S>(req: &Request<S>) -> Option<&PublishProperties> {
+    match req.message() {
+        MqttMessage::Msg(msg) => msg.properties.as_ref(),
+        _ => None,
+    }
+}
+
+/// 实际收到消息的主题，事件消息为对应的事件主题
+pub struct Topic(pub String);
+impl<S> FromRequest<S> for Topic {
+    type Rejection = Infallible;
+
+    fn from_request(req: &Request<S>) -> Result<Self, Infallible> {
+        Ok(Self(req.topic()))
+    }
+}
+
+/// PUBLISH 报文的固定头信息，事件消息会被拒绝
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Meta {
+    pub qos: QoS,
+    pub retain: bool,
+    pub dup: bool,
+    pub pkid: u16,
+}
+impl<S> FromRequest<S> for Meta {
+    type Rejection = RouterError;
+
+    fn from_request(req: &Request<S>) -> Result<Self, RouterError> {
+        let msg = publish(req)?;
+        Ok(Meta {
+            qos: msg.qos,
+            retain: msg.retain,
+            dup: msg.dup,
+            pkid: msg.pkid,
+        })
+    }
+}
+
+/// MQTT 5 用户属性，没有时为空
+pub struct UserProperties(pub Vec<(String, String)>);
+impl<S> FromRequest<S> for UserProperties {
+    type Rejection = Infallible;
+
+    fn from_request(req: &Request<S>) -> Result<Self, Infallible> {
+        let props = properties(req)
+            .map(|p| p.user_properties.clone())
+            .unwrap_or_default();
+        Ok(Self(props))
+    }
+}
+
+impl UserProperties {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+macro_rules! impl_property_extractor {
+    ($(#[$doc:meta])* $name:ident($ty:ty), $field:ident) => {
+        $(#[$doc])*
+        pub struct $name(pub $ty);
+        impl<S> FromRequest<S> for $name {
+            type Rejection = RouterError;
+
+            fn from_request(req: &Request<S>) -> Result<Self, RouterError> {
+                let value = properties(req)
+                    .and_then(|p| p.$field.clone())
+                    .ok_or(RouterError::MissingProperty(stringify!($field)))?;
+                Ok(Self(value))
+            }
+        }
+    };
+}
+
+impl_property_extractor!(
+    /// content type 属性，消息没有该属性时拒绝
+    ContentType(String),
+    content_type
+);
+impl_property_extractor!(
+    /// response topic 属性，消息没有该属性时拒绝
+    ResponseTopic(String),
+    response_topic
+);
+impl_property_extractor!(
+    /// correlation data 属性，消息没有该属性时拒绝
+    CorrelationData(Bytes),
+    correlation_data
+);
+impl_property_extractor!(
+    /// message expiry interval 属性（秒），消息没有该属性时拒绝
+    MessageExpiry(u32),
+    message_expiry_interval
+);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use toolkit_rs::AppResult;
+
+    #[test]
+    fn extract_metadata() -> AppResult {
+        let properties = PublishProperties {
+            content_type: Some("application/json".into()),
+            response_topic: Some("reply/1".into()),
+            correlation_data: Some(Bytes::from_static(b"c1")),
+            message_expiry_interval: Some(30),
+            user_properties: vec![("trace".into(), "abc".into())],
+            ..PublishProperties::default()
+        };
+        let mut msg = Message::new("dev/1/cmd", QoS::AtLeastOnce, "{}", Some(properties));
+        msg.pkid = 7;
+        msg.retain = true;
+        let req = Request::new(MqttMessage::Msg(msg), ());
+
+        assert_eq!(Topic::from_request(&req)?.0, "dev/1/cmd");
+        let meta = Meta::from_request(&req)?;
+        assert_eq!(
+            meta,
+            Meta {
+                qos: QoS::AtLeastOnce,
+                retain: true,
+                dup: false,
+                pkid: 7
+            }
+        );
+        assert_eq!(
+            UserProperties::from_request(&req)?.get("trace"),
+            Some("abc")
+        );
+        assert_eq!(ContentType::from_request(&req)?.0, "application/json");
+        assert_eq!(ResponseTopic::from_request(&req)?.0, "reply/1");
+        assert_eq!(CorrelationData::from_request(&req)?.0, "c1");
+        assert_eq!(MessageExpiry::from_request(&req)?.0, 30);
+
+        let req = Request::new(MqttMessage::EvtConnected, ());
+        assert!(Meta::from_request(&req).is_err());
+        assert!(ResponseTopic::from_request(&req).is_err());
+        assert!(UserProperties::from_request(&req)?.0.is_empty());
+        Ok(())
+    }
+}
@@ -1,18 +1,40 @@
+mod alias;
 mod client;
+mod codec;
+mod compress;
 mod conn;
+mod extract;
 mod manager;
+mod middleware;
+mod response;
 mod router;
+mod rpc;
+#[cfg(feature = "tower")]
+mod service;
+mod template;
 pub mod tls;
+mod topic;
+mod trie;
 pub mod types;
-pub use crate::client::{Client, MqttClient};
+pub use crate::client::{Client, ClientStats, MqttClient};
+use alias::*;
 use anyhow::anyhow;
+pub use codec::*;
+pub use compress::*;
 use conn::*;
+pub use extract::*;
 use manager::*;
+pub use middleware::*;
+pub use response::*;
 pub use router::*;
+use rpc::*;
 pub use rumqttc::v5::mqttbytes::QoS;
 pub use rumqttc::v5::mqttbytes::v5::{ConnectProperties, Publish as Message};
 pub use rumqttc::v5::{AsyncClient, MqttOptions as Config};
+pub use template::*;
 pub use tls::*;
+pub use topic::*;
+pub use trie::*;
 pub use types::*;
 
 use std::sync::Arc;
@@ -28,12 +50,20 @@ pub async fn start_with_cfg(
     producter: mpsc::Sender<MqttMessage>,
 ) -> MqttResult<MqttClient> {
     //init
+    let rpc = Arc::new(Rpc::new(cfg.client_id()));
+    let aliases = Arc::new(OutgoingAliases::default());
     let (conn, c) = Conn::new(cfg);
     let (state_tx, state_rx) = watch::channel(State::Pending);
     let (close_send, close_recv) = watch::channel(false);
 
-    let client = Arc::new(Client::new(state_rx, c, close_send));
-    Manager::new(state_tx, conn, producter).run(close_recv.clone());
+    let client = Arc::new(Client::with_shared(
+        state_rx,
+        c,
+        close_send,
+        rpc.clone(),
+        aliases.clone(),
+    ));
+    Manager::new(state_tx, conn, producter, rpc, aliases).run(close_recv.clone());
 
     let mut timeout = timeout.as_secs();
     if timeout <= 0 {
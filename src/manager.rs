@@ -1,4 +1,6 @@
-use crate::{Conn, Message, MqttMessage, State};
+use crate::{Conn, IncomingAliases, Message, MqttMessage, OutgoingAliases, Rpc, State};
+use rumqttc::v5::mqttbytes::v5::ConnAckProperties;
+use std::sync::Arc;
 
 use std::time::Duration;
 use tokio::{
@@ -10,11 +12,14 @@ pub(crate) struct Manager {
     state: watch::Sender<State>,
     producter: Sender<MqttMessage>,
     conn: Conn,
+    rpc: Arc<Rpc>,
+    aliases: Arc<OutgoingAliases>,
+    incoming_aliases: IncomingAliases,
 }
 
 pub(crate) enum MqttEventData {
     Error(String),
-    Connected,
+    Connected(Option<ConnAckProperties>),
     Disconnected,
     IncomeMsg(Message),
 }
@@ -24,11 +29,16 @@ impl Manager {
         state: watch::Sender<State>,
         conn: Conn,
         producter: Sender<MqttMessage>,
+        rpc: Arc<Rpc>,
+        aliases: Arc<OutgoingAliases>,
     ) -> Self {
         Manager {
             state,
             producter,
             conn,
+            rpc,
+            aliases,
+            incoming_aliases: IncomingAliases::default(),
         }
     }
     pub(crate) fn run(mut self, mut cancel_recv: watch::Receiver<bool>) {
@@ -48,26 +58,45 @@ impl Manager {
                         };
                         match s {
                             MqttEventData::Disconnected => {
+                                // 别名只在一次连接内有效
+                                self.aliases.invalidate(&mut self.conn.eventloop);
+                                self.incoming_aliases.clear();
                                 if *self.state.borrow() != State::Disconnected {
                                     self.state.send(State::Disconnected).ok();
                                     self.producter.send(MqttMessage::EvtDisconnected).await.ok();
                                 }
                                 tokio::time::sleep(Duration::from_secs(5)).await;
                             }
-                            MqttEventData::Connected => {
+                            MqttEventData::Connected(props) => {
+                                let (alias_max, info) = props
+                                    .map(|p| (p.topic_alias_max, p.response_information))
+                                    .unwrap_or_default();
+                                self.aliases.reset(alias_max.unwrap_or(0));
+                                self.incoming_aliases.clear();
+                                self.rpc.set_response_information(info);
                                 if *self.state.borrow() != State::Connected {
                                     self.state.send(State::Connected).ok();
                                     self.producter.send(MqttMessage::EvtConnected).await.ok();
                                 }
                             }
                             MqttEventData::Error(e) => {
+                                // poll 出错时连接已经断开
+                                self.aliases.invalidate(&mut self.conn.eventloop);
                                 let is_error = matches!(*self.state.borrow(), State::Error(_));
                                 if is_error {
                                     self.state.send(State::Error(e.to_string())).ok();
                                     self.producter.send(MqttMessage::EvtError(e.to_string())).await.ok();
                                 }
                             }
-                            MqttEventData::IncomeMsg(msg) => {
+                            MqttEventData::IncomeMsg(mut msg) => {
+                                if !self.incoming_aliases.resolve(&mut msg) {
+                                    log::warn!("drop publish with unknown topic alias: {:?}", msg.properties);
+                                    continue;
+                                }
+                                // 请求的回复不进入路由通道
+                                let Some(msg) = self.rpc.resolve(msg) else {
+                                    continue;
+                                };
                                 self.producter.send(MqttMessage::Msg(msg)).await.ok();
                             }
                         }
@@ -0,0 +1,156 @@
+use crate::{BoxFuture, Dispatcher, HandlerFn, MqttResult, Request, Response};
+use std::future::Future;
+use std::sync::Arc;
+
+pub type BoxMiddleware<S> = Arc<dyn Middleware<S>>;
+
+/// 中间件：可以读取或修改 [`Request`]、直接返回结果而不调用后续处理，
+/// 或者对 `next.run(req)` 的返回值做后处理。
+///
+/// 普通的 `async fn(Request<S>, Next<S>) -> MqttResult<Response>` 自动实现该 trait。
+pub trait Middleware<S>: Send + Sync + 'static {
+    fn call(&self, req: Request<S>, next: Next<S>) -> BoxFuture<MqttResult<Response>>;
+}
+
+impl<S, F, Fut> Middleware<S> for F
+where
+    F: Fn(Request<S>, Next<S>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = MqttResult<Response>> + Send + 'static,
+{
+    fn call(&self, req: Request<S>, next: Next<S>) -> BoxFuture<MqttResult<Response>> {
+        Box::pin(self(req, next))
+    }
+}
+
+/// 中间件链中剩余的部分，调用 [`Next::run`] 继续执行内层中间件和 handler
+pub struct Next<S> {
+    handler: HandlerFn<S>,
+    layers: Arc<[BoxMiddleware<S>]>,
+    index: usize,
+}
+
+impl<S: 'static> Next<S> {
+    // layers 中靠后的中间件先执行（位于外层）
+    pub(crate) fn new(handler: HandlerFn<S>, layers: Arc<[BoxMiddleware<S>]>) -> Self {
+        let index = layers.len();
+        Next {
+            handler,
+            layers,
+            index,
+        }
+    }
+
+    pub fn run(self, req: Request<S>) -> BoxFuture<MqttResult<Response>> {
+        if self.index == 0 {
+            return (self.handler)(req);
+        }
+        let index = self.index - 1;
+        let layer = self.layers[index].clone();
+        layer.call(
+            req,
+            Next {
+                handler: self.handler,
+                layers: self.layers,
+                index,
+            },
+        )
+    }
+}
+
+impl<S> Dispatcher<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    /// 给单个路由添加中间件，后添加的中间件位于外层、先执行
+    pub fn layer<M: Middleware<S>>(self, middleware: M) -> Self {
+        self.with_layers(Arc::from(vec![Arc::new(middleware) as _]))
+    }
+
+    pub(crate) fn with_layers(self, layers: Arc<[BoxMiddleware<S>]>) -> Self {
+        if layers.is_empty() {
+            return self;
+        }
+        let inner = self.func.clone();
+        let func: HandlerFn<S> =
+            Arc::new(move |req: Request<S>| Next::new(inner.clone(), layers.clone()).run(req));
+        Dispatcher { func, ..self }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::router::test::{publish, test_client};
+    use crate::{MqttRouter, Payload, RouterError, StateHandle, handler};
+    use anyhow::anyhow;
+    use std::sync::Mutex;
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    async fn record(
+        Payload(payload): Payload<String>,
+        StateHandle(log): StateHandle<Log>,
+    ) -> MqttResult {
+        log.lock().unwrap().push(format!("handler:{payload}"));
+        Ok(())
+    }
+
+    fn tag(name: &'static str) -> impl Middleware<Log> {
+        move |req: Request<Log>, next: Next<Log>| async move {
+            req.state().lock().unwrap().push(format!("{name}:before"));
+            let log = req.state().clone();
+            let res = next.run(req).await;
+            log.lock().unwrap().push(format!("{name}:after"));
+            res
+        }
+    }
+
+    #[tokio::test]
+    async fn layers_wrap_in_api_order() -> MqttResult {
+        let mut router = MqttRouter::<Log>::new(test_client());
+        router.add("a/{id}", handler(record).layer(tag("route")))?;
+        router.layer(tag("inner")).layer(tag("outer"));
+
+        let log = Log::default();
+        router.dispatch(publish("a/1", "x"), log.clone()).await?;
+        assert_eq!(
+            *log.lock().unwrap(),
+            [
+                "outer:before",
+                "inner:before",
+                "route:before",
+                "handler:x",
+                "route:after",
+                "inner:after",
+                "outer:after",
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn layer_can_rewrite_and_short_circuit() -> MqttResult {
+        let mut router = MqttRouter::<Log>::new(test_client());
+        router.add("a/{id}", record)?;
+        router.layer(|mut req: Request<Log>, next: Next<Log>| async move {
+            if req.topic() == "a/deny" {
+                return Err(anyhow!(RouterError::PayloadIsNotUtf8));
+            }
+            if let crate::MqttMessage::Msg(msg) = req.message_mut() {
+                msg.payload = msg.payload.to_ascii_uppercase().into();
+            }
+            next.run(req).await
+        });
+
+        let log = Log::default();
+        router.dispatch(publish("a/1", "x"), log.clone()).await?;
+        assert!(
+            router
+                .dispatch(publish("a/deny", "y"), log.clone())
+                .await
+                .is_err()
+        );
+        assert_eq!(*log.lock().unwrap(), ["handler:X"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,154 @@
+use crate::{Codec, Json, MqttResult, Payload, RawPayload, UserProperties};
+use bytes::Bytes;
+use rumqttc::v5::mqttbytes::v5::PublishProperties;
+use serde::Serialize;
+use std::fmt::{self, Display};
+
+/// handler 的返回值。收到的消息带有 response topic 且 `payload` 不为空时，
+/// 路由会把它发布到 response topic，并带上原消息的 correlation data
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Response {
+    pub payload: Option<Bytes>,
+    pub content_type: Option<String>,
+    pub user_properties: Vec<(String, String)>,
+}
+
+impl Response {
+    pub fn new<P: Into<Bytes>>(payload: P) -> Self {
+        Response {
+            payload: Some(payload.into()),
+            ..Response::default()
+        }
+    }
+
+    /// 提取器拒绝请求时回复的错误响应，payload 为错误信息，
+    /// 并带有用户属性 `status=error`
+    pub fn error<E: Display>(error: E) -> Self {
+        Response {
+            payload: Some(Bytes::from(error.to_string())),
+            content_type: Some("text/plain".into()),
+            user_properties: vec![("status".into(), "error".into())],
+        }
+    }
+
+    /// 用 `codec` 编码 payload 并设置对应的 content type
+    pub fn encode<C: Codec<T>, T>(codec: &C, value: &T) -> MqttResult<Self> {
+        Ok(Response {
+            payload: Some(codec.encode(value)?),
+            content_type: Some(codec.content_type().into()),
+            ..Response::default()
+        })
+    }
+
+    pub(crate) fn into_properties(
+        self,
+        correlation_data: Option<Bytes>,
+    ) -> (Option<Bytes>, PublishProperties) {
+        let properties = PublishProperties {
+            content_type: self.content_type,
+            user_properties: self.user_properties,
+            correlation_data,
+            ..PublishProperties::default()
+        };
+        (self.payload, properties)
+    }
+}
+
+pub trait IntoResponse {
+    fn into_response(self) -> MqttResult<Response>;
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> MqttResult<Response> {
+        Ok(self)
+    }
+}
+
+/// 不回复
+impl IntoResponse for () {
+    fn into_response(self) -> MqttResult<Response> {
+        Ok(Response::default())
+    }
+}
+
+/// `Err` 不会回复，作为派发错误返回
+impl<T, E> IntoResponse for Result<T, E>
+where
+    T: IntoResponse,
+    E: Into<anyhow::Error>,
+{
+    fn into_response(self) -> MqttResult<Response> {
+        self.map_err(Into::into)?.into_response()
+    }
+}
+
+impl IntoResponse for Bytes {
+    fn into_response(self) -> MqttResult<Response> {
+        Ok(Response::new(self))
+    }
+}
+
+impl IntoResponse for Vec<u8> {
+    fn into_response(self) -> MqttResult<Response> {
+        Ok(Response::new(self))
+    }
+}
+
+impl IntoResponse for String {
+    fn into_response(self) -> MqttResult<Response> {
+        Ok(Response::new(self))
+    }
+}
+
+impl IntoResponse for &'static str {
+    fn into_response(self) -> MqttResult<Response> {
+        Ok(Response::new(self))
+    }
+}
+
+impl<T: Display> IntoResponse for Payload<T> {
+    fn into_response(self) -> MqttResult<Response> {
+        Ok(Response::new(self.0.to_string()))
+    }
+}
+
+impl<T: Into<Bytes>> IntoResponse for RawPayload<T> {
+    fn into_response(self) -> MqttResult<Response> {
+        Ok(Response::new(self.0))
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> MqttResult<Response> {
+        Ok(Response {
+            payload: Some(self.to_bytes()?),
+            content_type: Some("application/json".into()),
+            ..Response::default()
+        })
+    }
+}
+
+impl<R: IntoResponse> IntoResponse for (R, UserProperties) {
+    fn into_response(self) -> MqttResult<Response> {
+        let mut res = self.0.into_response()?;
+        res.user_properties.extend(self.1.0);
+        Ok(res)
+    }
+}
+
+/// 附加在提取器错误上的标记，路由据此向请求方回复错误响应。
+/// 通过 `downcast_ref` 仍然可以取到原始的提取错误
+#[derive(Debug)]
+pub struct Rejected(String);
+
+impl Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "request rejected: {}", self.0)
+    }
+}
+
+pub(crate) fn rejected<E: Into<anyhow::Error>>(error: E) -> anyhow::Error {
+    let error = error.into();
+    let message = error.to_string();
+    error.context(Rejected(message))
+}
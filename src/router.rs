@@ -1,12 +1,27 @@
-use crate::{MqttClient, MqttMessage, MqttResult, QoS, types};
-use matchit::Router;
+use crate::codec::{request_format, scope_format};
+use crate::compress::{decompress_message, is_compressed};
+use crate::{
+    BoxMiddleware, CompressError, DEFAULT_MAX_DECOMPRESSED, Format, IntoResponse, Middleware,
+    MqttClient, MqttMessage, MqttResult, Next, QoS, Rejected, Response, TopicError, TopicTrie,
+    rejected, types, validate_topic_filter,
+};
+use bytes::Bytes;
+use serde::Serialize;
 use serde::de::DeserializeOwned;
 use serde_json::Value as JsonValue;
+use std::any::Any;
+use std::collections::HashMap;
+use std::convert::Infallible;
 use std::future::Future;
+use std::panic::{AssertUnwindSafe, catch_unwind};
 use std::pin::Pin;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
 use thiserror::Error;
+use tokio::sync::{Semaphore, mpsc, oneshot};
+use tokio::task::{JoinHandle, JoinSet};
 use toolkit_rs::AppResult;
 
 #[derive(Error, Debug)]
@@ -16,23 +31,201 @@ pub enum RouterError {
     #[error("failed to parse payload {text}: {error}")]
     PayloadParseFailed { text: String, error: String },
     #[error(transparent)]
-    InsertError(#[from] matchit::InsertError),
+    JsonError(#[from] serde_json::Error),
+    #[error("route {route} conflicts with existing route {existing}")]
+    RouteConflict { route: String, existing: String },
+    #[error("invalid route {route}: {reason}")]
+    InvalidRoute { route: String, reason: &'static str },
     #[error(transparent)]
-    MatchError(#[from] matchit::MatchError),
+    InvalidTopic(#[from] TopicError),
+    #[error("invalid json payload on {topic}: {source}, payload: {excerpt}")]
+    JsonRejected {
+        topic: String,
+        excerpt: String,
+        source: serde_json::Error,
+    },
+    #[error("message is not a publish: {0}")]
+    NotAPublish(String),
+    #[error("publish property {0} is missing")]
+    MissingProperty(&'static str),
+    #[error("handler for {topic} timed out after {timeout:?}")]
+    HandlerTimeout { topic: String, timeout: Duration },
+    #[error("handler for {topic} panicked: {message}")]
+    HandlerPanicked { topic: String, message: String },
     #[error(transparent)]
-    JsonError(#[from] serde_json::Error),
+    Decompress(#[from] CompressError),
     #[error(transparent)]
     Any(#[from] anyhow::Error),
 }
 
+/// handler 超时、panic 或 payload 解压失败时传给错误回调的报告
+#[derive(Debug)]
+pub struct DispatchReport<'a> {
+    pub topic: &'a str,
+    pub error: &'a RouterError,
+}
+
+pub type ErrorHook = Arc<dyn Fn(&DispatchReport<'_>) + Send + Sync>;
+pub type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+pub(crate) type HandlerFn<S> =
+    Arc<dyn Fn(Request<S>) -> BoxFuture<MqttResult<Response>> + Send + Sync>;
+
 pub struct Request<S> {
     params: JsonValue,
     message: MqttMessage,
     state: S,
+    // 路由设置的编码格式，消息没有 content type 时使用
+    pub(crate) format: Option<Format>,
+}
+
+impl<S> Request<S> {
+    pub fn new(message: MqttMessage, state: S) -> Self {
+        Request {
+            params: JsonValue::Null,
+            message,
+            state,
+            format: None,
+        }
+    }
+
+    pub fn topic(&self) -> String {
+        self.message.callback_router_topic()
+    }
+
+    pub fn message(&self) -> &MqttMessage {
+        &self.message
+    }
+
+    pub fn message_mut(&mut self) -> &mut MqttMessage {
+        &mut self.message
+    }
+
+    pub fn params(&self) -> &JsonValue {
+        &self.params
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    pub fn into_parts(self) -> (MqttMessage, S) {
+        (self.message, self.state)
+    }
 }
 
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` cannot be used as a handler argument",
+    label = "does not implement `FromRequest<{S}>`",
+    note = "handler arguments must be extractors such as `Payload<T>`, `Json<T>`, `Params<T>`, `StateHandle<S>` or `Topic`",
+    note = "`StateHandle<T>` only works when `T` is the router state type `{S}`"
+)]
 pub trait FromRequest<S>: Sized {
-    fn from_request(request: &Request<S>) -> AppResult<Self>;
+    /// 提取失败时的错误，handler 不会被调用
+    type Rejection: Into<anyhow::Error>;
+
+    fn from_request(request: &Request<S>) -> Result<Self, Self::Rejection>;
+}
+
+/// 提取失败时为 `None`，handler 照常执行
+impl<S, T> FromRequest<S> for Option<T>
+where
+    T: FromRequest<S>,
+{
+    type Rejection = Infallible;
+
+    fn from_request(req: &Request<S>) -> Result<Self, Infallible> {
+        Ok(T::from_request(req).ok())
+    }
+}
+
+/// 把提取错误交给 handler 处理，例如回复结构化的错误信息
+impl<S, T> FromRequest<S> for Result<T, T::Rejection>
+where
+    T: FromRequest<S>,
+{
+    type Rejection = Infallible;
+
+    fn from_request(req: &Request<S>) -> Result<Self, Infallible> {
+        Ok(T::from_request(req))
+    }
+}
+
+/// 可以在提取时 await 的提取器，例如查询设备信息、检查缓存或申请许可。
+/// 实现 [`FromRequest`] 的同步提取器自动实现该 trait，两者只需实现其一。
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` cannot be used as a handler argument",
+    label = "does not implement `FromRequest<{S}>` or `FromRequestAsync<{S}>`"
+)]
+pub trait FromRequestAsync<S, M = ViaAsync>: Sized {
+    type Rejection: Into<anyhow::Error>;
+
+    fn from_request_async(
+        request: &Request<S>,
+    ) -> impl Future<Output = Result<Self, Self::Rejection>> + Send;
+}
+
+// 区分同步与异步提取器实现的标记类型
+#[doc(hidden)]
+pub enum ViaSync {}
+#[doc(hidden)]
+pub enum ViaAsync {}
+
+impl<S, T> FromRequestAsync<S, ViaSync> for T
+where
+    S: Sync,
+    T: FromRequest<S>,
+{
+    type Rejection = T::Rejection;
+
+    async fn from_request_async(req: &Request<S>) -> Result<Self, Self::Rejection> {
+        T::from_request(req)
+    }
+}
+
+impl<S, T> FromRequestAsync<S, ViaAsync> for Option<T>
+where
+    S: Sync,
+    T: FromRequestAsync<S, ViaAsync>,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_async(req: &Request<S>) -> Result<Self, Infallible> {
+        Ok(T::from_request_async(req).await.ok())
+    }
+}
+
+impl<S, T> FromRequestAsync<S, ViaAsync> for Result<T, T::Rejection>
+where
+    S: Sync,
+    T: FromRequestAsync<S, ViaAsync>,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_async(req: &Request<S>) -> Result<Self, Infallible> {
+        Ok(T::from_request_async(req).await)
+    }
+}
+
+// 事件消息没有 payload，使用事件名作为内容
+fn message_payload(message: &MqttMessage) -> Bytes {
+    match message {
+        MqttMessage::Msg(s) => s.payload.clone(),
+        MqttMessage::EvtClosed => Bytes::from_static(b"Closed"),
+        MqttMessage::EvtError(e) => Bytes::from(e.clone()),
+        MqttMessage::EvtConnected => Bytes::from_static(b"Connected"),
+        MqttMessage::EvtDisconnected => Bytes::from_static(b"Disconnected"),
+    }
+}
+
+// 错误信息里只保留 payload 的前一部分
+fn payload_excerpt(payload: &[u8]) -> String {
+    const MAX: usize = 64;
+    let text = String::from_utf8_lossy(&payload[..payload.len().min(MAX)]);
+    if payload.len() > MAX {
+        format!("{text}...")
+    } else {
+        text.into_owned()
+    }
 }
 
 pub struct Payload<T>(pub T);
@@ -41,16 +234,11 @@ where
     T: FromStr,
     <T as FromStr>::Err: std::fmt::Debug,
 {
-    fn from_request(req: &Request<S>) -> AppResult<Payload<T>> {
-        let msg = match &req.message {
-            MqttMessage::Msg(s) => {
-                std::str::from_utf8(&s.payload).map_err(|_| RouterError::PayloadIsNotUtf8)?
-            }
-            MqttMessage::EvtClosed => "Closed",
-            MqttMessage::EvtError(e) => &e.to_string(),
-            MqttMessage::EvtConnected => "Connected",
-            MqttMessage::EvtDisconnected => "Disconnected",
-        };
+    type Rejection = RouterError;
+
+    fn from_request(req: &Request<S>) -> Result<Self, RouterError> {
+        let payload = message_payload(&req.message);
+        let msg = std::str::from_utf8(&payload).map_err(|_| RouterError::PayloadIsNotUtf8)?;
 
         let res: T = msg.parse().map_err(|err| RouterError::PayloadParseFailed {
             text: msg.to_string(),
@@ -61,12 +249,63 @@ where
     }
 }
 
+/// 原始 payload，`RawPayload(Bytes)` 与消息共享内存不做拷贝，也可以用 `RawPayload<Vec<u8>>`
+pub struct RawPayload<T = Bytes>(pub T);
+impl<S, T> FromRequest<S> for RawPayload<T>
+where
+    T: From<Bytes>,
+{
+    type Rejection = Infallible;
+
+    fn from_request(req: &Request<S>) -> Result<Self, Infallible> {
+        Ok(Self(T::from(message_payload(&req.message))))
+    }
+}
+
+/// payload 转为字符串，非 UTF-8 的字节替换为 `U+FFFD`
+pub struct Utf8Lossy(pub String);
+impl<S> FromRequest<S> for Utf8Lossy {
+    type Rejection = Infallible;
+
+    fn from_request(req: &Request<S>) -> Result<Self, Infallible> {
+        let payload = message_payload(&req.message);
+        Ok(Self(String::from_utf8_lossy(&payload).into_owned()))
+    }
+}
+
+/// 以 JSON 解析 payload；发布时用 [`Json::to_bytes`] 或 [`crate::Client::publish_json`] 序列化
+pub struct Json<T>(pub T);
+impl<S, T> FromRequest<S> for Json<T>
+where
+    T: DeserializeOwned,
+{
+    type Rejection = RouterError;
+
+    fn from_request(req: &Request<S>) -> Result<Self, RouterError> {
+        let payload = message_payload(&req.message);
+        let res = serde_json::from_slice(&payload).map_err(|source| RouterError::JsonRejected {
+            topic: req.topic(),
+            excerpt: payload_excerpt(&payload),
+            source,
+        })?;
+        Ok(Self(res))
+    }
+}
+
+impl<T: Serialize> Json<T> {
+    pub fn to_bytes(&self) -> MqttResult<Bytes> {
+        Ok(Bytes::from(serde_json::to_vec(&self.0)?))
+    }
+}
+
 pub struct Params<T>(pub T);
 impl<S, T> FromRequest<S> for Params<T>
 where
     T: DeserializeOwned,
 {
-    fn from_request(request: &Request<S>) -> AppResult<Self> {
+    type Rejection = RouterError;
+
+    fn from_request(request: &Request<S>) -> Result<Self, RouterError> {
         let parsed: T = serde_json::from_value(request.params.clone())?;
         Ok(Self(parsed))
     }
@@ -77,7 +316,9 @@ impl<S> FromRequest<S> for StateHandle<S>
 where
     S: Clone + Send + Sync + 'static,
 {
-    fn from_request(request: &Request<S>) -> AppResult<Self> {
+    type Rejection = Infallible;
+
+    fn from_request(request: &Request<S>) -> Result<Self, Infallible> {
         Ok(Self(request.state.clone()))
     }
 }
@@ -86,56 +327,442 @@ pub struct Dispatcher<S = ()>
 where
     S: Clone + Send + Sync,
 {
-    func: Box<dyn Fn(Request<S>) -> Pin<Box<dyn Future<Output = MqttResult> + Send>> + Send + Sync>,
+    pub(crate) func: HandlerFn<S>,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) format: Option<Format>,
+    // 挂载时带过来的子路由错误回调
+    pub(crate) on_error: Option<ErrorHook>,
 }
 
-impl<S> Dispatcher<S>
+impl<S> Clone for Dispatcher<S>
 where
     S: Clone + Send + Sync,
 {
-    pub async fn call(&self, req: Request<S>) -> MqttResult {
-        (self.func)(req).await
+    fn clone(&self) -> Self {
+        Self {
+            func: self.func.clone(),
+            timeout: self.timeout,
+            format: self.format,
+            on_error: self.on_error.clone(),
+        }
+    }
+}
+
+impl<S> Dispatcher<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    /// 执行 handler，超时或 panic 时返回 [`RouterError`]
+    pub async fn call(&self, req: Request<S>) -> MqttResult<Response> {
+        self.invoke(req, self.timeout, Arc::from(Vec::new()))
+            .await?
     }
 
     pub fn new(
-        func: Box<
-            dyn Fn(Request<S>) -> Pin<Box<dyn Future<Output = MqttResult> + Send>> + Send + Sync,
-        >,
+        func: Box<dyn Fn(Request<S>) -> BoxFuture<MqttResult<Response>> + Send + Sync>,
     ) -> Self {
-        Self { func }
+        Self {
+            func: Arc::from(func),
+            timeout: None,
+            format: None,
+            on_error: None,
+        }
+    }
+
+    /// 设置该路由的超时时间，优先于 [`MqttRouter::default_timeout`]
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// 设置该路由的编码格式，消息没有 content type 时 [`crate::Encoded`] 按该格式解码
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    // 外层错误为超时或 panic，内层为 handler 自身的返回值
+    async fn invoke(
+        &self,
+        mut req: Request<S>,
+        timeout: Option<Duration>,
+        layers: Arc<[BoxMiddleware<S>]>,
+    ) -> Result<MqttResult<Response>, RouterError> {
+        let topic = req.message.callback_router_topic();
+        if self.format.is_some() {
+            req.format = self.format;
+        }
+        // content type 无法识别时由提取器拒绝，这里按 JSON 处理
+        let format = request_format(&req).unwrap_or_default();
+        let next = Next::new(self.func.clone(), layers);
+        let fut = match catch_unwind(AssertUnwindSafe(|| next.run(req))) {
+            Ok(fut) => scope_format(format, CatchUnwind(fut)),
+            Err(panic) => return Err(panicked(topic, panic)),
+        };
+
+        let res = match timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, fut).await {
+                Ok(res) => res,
+                Err(_) => return Err(RouterError::HandlerTimeout { topic, timeout }),
+            },
+            None => fut.await,
+        };
+        res.map_err(|panic| panicked(topic, panic))
+    }
+}
+
+fn panicked(topic: String, panic: Box<dyn Any + Send>) -> RouterError {
+    let message = if let Some(s) = panic.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = panic.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_string()
+    };
+    RouterError::HandlerPanicked { topic, message }
+}
+
+// 捕获 handler future 在 poll 时产生的 panic
+struct CatchUnwind<F>(F);
+
+impl<F: Future + Unpin> Future for CatchUnwind<F> {
+    type Output = Result<F::Output, Box<dyn Any + Send>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let inner = &mut self.0;
+        match catch_unwind(AssertUnwindSafe(|| Pin::new(inner).poll(cx))) {
+            Ok(Poll::Pending) => Poll::Pending,
+            Ok(Poll::Ready(v)) => Poll::Ready(Ok(v)),
+            Err(panic) => Poll::Ready(Err(panic)),
+        }
+    }
+}
+
+/// 把 handler 转换为 [`Dispatcher`]，用于设置单个路由的超时等选项：
+/// `router.add("jobs/{id}", handler(job).with_timeout(Duration::from_secs(5)))`
+pub fn handler<T, S, F>(func: F) -> Dispatcher<S>
+where
+    S: Clone + Send + Sync,
+    F: MakeDispatcher<T, S>,
+{
+    F::make_dispatcher(func)
+}
+
+pub type OrderKeyFn = Arc<dyn Fn(&MqttMessage) -> Option<String> + Send + Sync>;
+
+/// 并发派发时消息的顺序约束
+#[derive(Clone, Default)]
+pub enum DispatchOrder {
+    /// 不保证顺序，有空闲并发名额就立即处理
+    #[default]
+    Unordered,
+    /// 同一主题的消息按到达顺序依次处理
+    PerTopic,
+    /// 返回相同 key 的消息按到达顺序依次处理，返回 `None` 的消息不保证顺序
+    PerKey(OrderKeyFn),
+}
+
+impl DispatchOrder {
+    fn key(&self, message: &MqttMessage) -> Option<String> {
+        match self {
+            DispatchOrder::Unordered => None,
+            DispatchOrder::PerTopic => Some(message.callback_router_topic()),
+            DispatchOrder::PerKey(f) => f(message),
+        }
+    }
+}
+
+/// [`MqttRouter::serve`] 的配置
+#[derive(Clone)]
+pub struct ServeOptions {
+    /// 同时运行的 handler 上限
+    pub concurrency: usize,
+    /// 已接收但尚未处理完的消息上限，包括按顺序排队等待的消息；达到上限后暂停从通道读取
+    pub backlog: usize,
+    pub order: DispatchOrder,
+}
+
+impl Default for ServeOptions {
+    fn default() -> Self {
+        ServeOptions {
+            concurrency: 16,
+            backlog: 1024,
+            order: DispatchOrder::Unordered,
+        }
+    }
+}
+
+// 同一 key 的消息排成一条链，每个任务等待前一个任务结束后再执行
+#[derive(Default)]
+struct OrderChains {
+    seq: u64,
+    tails: HashMap<String, (u64, oneshot::Receiver<()>)>,
+}
+
+struct ChainLink {
+    key: String,
+    seq: u64,
+    prev: Option<oneshot::Receiver<()>>,
+    done: oneshot::Sender<()>,
+}
+
+impl OrderChains {
+    fn push(chains: &Mutex<Self>, key: String) -> ChainLink {
+        let (done, done_rx) = oneshot::channel();
+        let mut chains = chains.lock().unwrap_or_else(|e| e.into_inner());
+        chains.seq += 1;
+        let seq = chains.seq;
+        let prev = chains
+            .tails
+            .insert(key.clone(), (seq, done_rx))
+            .map(|(_, rx)| rx);
+        ChainLink {
+            key,
+            seq,
+            prev,
+            done,
+        }
+    }
+
+    fn finish(chains: &Mutex<Self>, link: ChainLink) {
+        let mut chains = chains.lock().unwrap_or_else(|e| e.into_inner());
+        if chains
+            .tails
+            .get(&link.key)
+            .is_some_and(|(s, _)| *s == link.seq)
+        {
+            chains.tails.remove(&link.key);
+        }
+        drop(link.done);
     }
 }
 
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` is not a valid MQTT handler",
+    label = "invalid handler",
+    note = "a handler is an `async fn` taking 0 to 16 arguments that implement `FromRequest<{S}>` or `FromRequestAsync<{S}>` and returning a type that implements `IntoResponse`, such as `MqttResult`",
+    note = "check that every argument is an extractor and that `StateHandle<T>` uses the router state type"
+)]
 pub trait MakeDispatcher<T, S: Clone + Send + Sync> {
     fn make_dispatcher(func: Self) -> Dispatcher<S>;
 }
 
+impl<S: Clone + Send + Sync> MakeDispatcher<Dispatcher<S>, S> for Dispatcher<S> {
+    fn make_dispatcher(func: Self) -> Dispatcher<S> {
+        func
+    }
+}
+
+// 请求/响应模式中回复的目标，来自请求消息的 response topic 与 correlation data
+struct ReplyTo {
+    topic: String,
+    correlation_data: Option<Bytes>,
+    qos: QoS,
+}
+
+impl ReplyTo {
+    fn from_message(message: &MqttMessage) -> Option<Self> {
+        let MqttMessage::Msg(msg) = message else {
+            return None;
+        };
+        let props = msg.properties.as_ref()?;
+        Some(ReplyTo {
+            topic: props.response_topic.clone()?,
+            correlation_data: props.correlation_data.clone(),
+            qos: msg.qos,
+        })
+    }
+}
+
+/// 一条消息匹配多个路由时的派发方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DispatchMode {
+    /// 只派发给最具体的路由
+    #[default]
+    MostSpecific,
+    /// 按添加顺序依次派发给所有匹配的路由，与 broker 对重叠订阅的投递方式一致。
+    /// 请求消息只由第一个匹配的路由回复
+    All,
+}
+
+/// [`DispatchMode::All`] 下有路由处理失败（包括只匹配一个路由时），其余路由仍会执行
+#[derive(Debug)]
+pub struct DispatchErrors {
+    pub topic: String,
+    /// 匹配的路由数
+    pub total: usize,
+    /// 失败的路由及其错误，按派发顺序
+    pub errors: Vec<(String, anyhow::Error)>,
+}
+
+impl std::fmt::Display for DispatchErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} of {} routes failed on {}",
+            self.errors.len(),
+            self.total,
+            self.topic
+        )?;
+        for (route, e) in &self.errors {
+            write!(f, "; {route}: {e}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for DispatchErrors {}
+
 pub struct MqttRouter<S = ()>
 where
     S: Clone + Send + Sync,
 {
-    router: Router<Dispatcher<S>>,
+    table: RwLock<RouteTable<S>>,
     client: MqttClient,
+    mode: DispatchMode,
+    max_decompressed: usize,
+    timeout: Option<Duration>,
+    on_error: Option<ErrorHook>,
+    layers: Arc<[BoxMiddleware<S>]>,
 }
 
 impl<S: Clone + Send + Sync + 'static> MqttRouter<S> {
     pub fn new(client: MqttClient) -> Self {
         Self {
-            router: Router::new(),
+            table: RwLock::new(RouteTable::new()),
             client,
+            mode: DispatchMode::default(),
+            max_decompressed: DEFAULT_MAX_DECOMPRESSED,
+            timeout: None,
+            on_error: None,
+            layers: Arc::from(Vec::new()),
+        }
+    }
+
+    /// 给所有路由（包括 [`types::UnkonwTopic`] 等事件路由）添加中间件，
+    /// 后添加的中间件位于外层、先执行，路由自身的中间件位于最内层
+    pub fn layer<M: Middleware<S>>(&mut self, middleware: M) -> &mut Self {
+        let mut layers = self.layers.to_vec();
+        layers.push(Arc::new(middleware));
+        self.layers = layers.into();
+        self
+    }
+
+    /// 一条消息匹配多个路由时的派发方式，默认为 [`DispatchMode::MostSpecific`]
+    pub fn dispatch_mode(&mut self, mode: DispatchMode) -> &mut Self {
+        self.mode = mode;
+        self
+    }
+
+    /// 带有 [`crate::CONTENT_ENCODING`] 用户属性的消息解压后 payload 的大小上限，
+    /// 超过时不调用 handler，默认为 [`DEFAULT_MAX_DECOMPRESSED`]
+    pub fn max_decompressed_size(&mut self, limit: usize) -> &mut Self {
+        self.max_decompressed = limit;
+        self
+    }
+
+    /// 所有未单独设置超时的路由使用的超时时间
+    pub fn default_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// handler 超时、panic 或 payload 解压失败时调用的回调
+    pub fn on_error<F>(&mut self, hook: F) -> &mut Self
+    where
+        F: Fn(&DispatchReport<'_>) + Send + Sync + 'static,
+    {
+        self.on_error = Some(Arc::new(hook));
+        self
+    }
+
+    // reply 为 false 时不回复请求消息
+    async fn call(&self, dispatcher: &Dispatcher<S>, req: Request<S>, reply: bool) -> MqttResult {
+        let topic = req.message.callback_router_topic();
+        let reply = reply.then(|| ReplyTo::from_message(&req.message)).flatten();
+        let timeout = dispatcher.timeout.or(self.timeout);
+        let res = match dispatcher.invoke(req, timeout, self.layers.clone()).await {
+            Ok(res) => res,
+            Err(error) => {
+                if let Some(hook) = dispatcher.on_error.as_ref().or(self.on_error.as_ref()) {
+                    hook(&DispatchReport {
+                        topic: &topic,
+                        error: &error,
+                    });
+                }
+                return Err(error.into());
+            }
+        };
+
+        match (res, reply) {
+            (Ok(response), Some(reply)) => self.respond(reply, response).await,
+            (Ok(_), None) => Ok(()),
+            (Err(e), Some(reply)) if e.downcast_ref::<Rejected>().is_some() => {
+                if let Err(pe) = self.respond(reply, Response::error(&e)).await {
+                    log::error!("publish error response failed,topic:{} {}", topic, pe);
+                }
+                Err(e)
+            }
+            (Err(e), _) => Err(e),
+        }
+    }
+
+    // 调用 handler 之前失败：通知错误回调，请求消息回复错误响应
+    async fn reject(
+        &self,
+        dispatcher: &Dispatcher<S>,
+        message: &MqttMessage,
+        error: RouterError,
+    ) -> anyhow::Error {
+        let topic = message.callback_router_topic();
+        if let Some(hook) = dispatcher.on_error.as_ref().or(self.on_error.as_ref()) {
+            hook(&DispatchReport {
+                topic: &topic,
+                error: &error,
+            });
         }
+        let e = rejected(error);
+        if let Some(reply) = ReplyTo::from_message(message)
+            && let Err(pe) = self.respond(reply, Response::error(&e)).await
+        {
+            log::error!("publish error response failed,topic:{} {}", topic, pe);
+        }
+        e
+    }
+
+    // 解压是同步的 CPU 计算，放到阻塞线程池执行，不占用异步工作线程
+    async fn decompress(&self, mut msg: crate::Message) -> Result<crate::Message, RouterError> {
+        let limit = self.max_decompressed;
+        tokio::task::spawn_blocking(move || {
+            decompress_message(&mut msg, limit)?;
+            Ok(msg)
+        })
+        .await
+        .map_err(|e| RouterError::Any(e.into()))?
+    }
+
+    async fn respond(&self, reply: ReplyTo, response: Response) -> MqttResult {
+        let (payload, properties) = response.into_properties(reply.correlation_data);
+        let Some(payload) = payload else {
+            return Ok(());
+        };
+        self.client
+            .publish_with_properties(reply.topic, payload, reply.qos, false, properties)
+            .await
     }
 
+    /// 订阅主题并添加路由。`{name}` 对应 `+`，最后一级的 `#` 或 `{*name}` 对应 `#`，
+    /// 匹配到的剩余部分通过参数 `rest`（或 `{*name}` 中的 name）取得，
+    /// 例如 `devices/{id}/logs/#` 同时匹配 `devices/1/logs` 与 `devices/1/logs/a/b`。
+    ///
+    /// `$share/{group}/jobs/{id}` 以共享订阅方式订阅，消息按 `jobs/{id}` 派发
     pub async fn subscribe<'a, P, T, F>(&mut self, path: P, handler: F, qos: QoS) -> MqttResult
     where
         P: Into<String>,
         F: MakeDispatcher<T, S>,
     {
-        let path = path.into();
-        self.client.subscribe(&route_to_topic(&path), qos).await?;
-        let dispatcher = F::make_dispatcher(handler);
-        self.router.insert(path, dispatcher)?;
-        Ok(())
+        self.subscribe_route(path.into(), F::make_dispatcher(handler), qos)
+            .await
     }
 
     pub fn add<'a, P, T, F>(&mut self, path: P, handler: F) -> MqttResult
@@ -143,127 +770,717 @@ impl<S: Clone + Send + Sync + 'static> MqttRouter<S> {
         P: Into<String>,
         F: MakeDispatcher<T, S>,
     {
-        let path = path.into();
-        let dispatcher = F::make_dispatcher(handler);
-        self.router.insert(path, dispatcher)?;
-        Ok(())
+        self.add_route(path.into(), F::make_dispatcher(handler), None)
     }
 
-    pub fn remove<'a, P, T, F>(&mut self, path: P) -> MqttResult
+    /// 添加路由并声明订阅使用的 QoS，但不立即订阅：在 `serve` 启动、重连或调用
+    /// [`MqttRouter::subscribe_pending`] 时订阅。用于构建之后通过 `nest` 挂载的子路由
+    pub fn route<P, T, F>(&mut self, path: P, handler: F, qos: QoS) -> MqttResult
     where
         P: Into<String>,
+        F: MakeDispatcher<T, S>,
     {
-        let path = path.into();
-        self.router.remove(path);
-        Ok(())
+        self.add_route(path.into(), F::make_dispatcher(handler), Some(qos))
     }
 
-    async fn invoke_unkown_handler(&self, req: Request<S>) -> AppResult {
-        let res = self.router.at(types::UnkonwTopic).ok();
-        if let Some(matched) = res {
-            let _ = matched.value.call(req).await;
-        } else {
-            log::warn!(
-                "dispatch error,topic:{}",
-                req.message.callback_router_topic()
-            );
-        }
+    /// 删除路由，路由已经订阅时同时取消订阅
+    pub async fn remove<P: Into<String>>(&mut self, path: P) -> MqttResult {
+        self.remove_route(path.into()).await
+    }
 
+    /// 转换为可以在多个任务间共享的 [`SharedRouter`]
+    pub fn into_shared(self) -> SharedRouter<S> {
+        SharedRouter(Arc::new(self))
+    }
+
+    async fn subscribe_route(
+        &self,
+        route: String,
+        dispatcher: Dispatcher<S>,
+        qos: QoS,
+    ) -> MqttResult {
+        let spec =
+            self.table
+                .write()
+                .unwrap()
+                .insert(route.clone(), dispatcher, Some(qos), true)?;
+        if let Err(e) = self.client.subscribe(&spec.topic, qos).await {
+            self.table.write().unwrap().remove(&route).ok();
+            return Err(e);
+        }
         Ok(())
     }
 
-    pub async fn dispatch(&self, message: MqttMessage, state: S) -> AppResult {
-        let topic = message.callback_router_topic();
+    fn add_route(&self, route: String, dispatcher: Dispatcher<S>, qos: Option<QoS>) -> MqttResult {
+        self.table
+            .write()
+            .unwrap()
+            .insert(route, dispatcher, qos, false)?;
+        Ok(())
+    }
 
-        let matched = match self.router.at(&topic) {
-            Ok(matched) => matched,
-            Err(_) => {
-                return self
-                    .invoke_unkown_handler(Request {
-                        params: JsonValue::Null,
-                        message,
-                        state,
-                    })
-                    .await;
-            }
-        };
+    async fn remove_route(&self, route: String) -> MqttResult {
+        let unused = self.table.write().unwrap().remove(&route)?;
+        if let Some(topic) = unused {
+            self.client.unsubscribe(&topic).await?;
+        }
+        Ok(())
+    }
 
-        let params = {
-            let mut value_map = serde_json::Map::new();
-            for (k, v) in matched.params.iter() {
-                value_map.insert(k.into(), v.into());
+    /// 订阅通过 [`MqttRouter::route`] 声明、还没有订阅的路由，`serve` 启动时与
+    /// 收到 [`MqttMessage::EvtConnected`] 时会自动调用。
+    /// 某个路由订阅失败时继续订阅其余的路由，返回第一个错误，失败的路由下次调用时重试
+    pub async fn subscribe_pending(&self) -> MqttResult {
+        let pending = self.table.read().unwrap().pending();
+        let mut failed = None;
+        for (key, topic, qos) in pending {
+            if let Err(e) = self.client.subscribe(&topic, qos).await {
+                log::warn!("subscribe route {} error: {}", topic, e);
+                failed.get_or_insert(e);
+                continue;
             }
-            if value_map.is_empty() {
-                serde_json::Value::Null
-            } else {
-                serde_json::Value::Object(value_map)
+            let mut table = self.table.write().unwrap();
+            // 订阅期间路由可能已被删除
+            if let Some(entry) = table.routes.get_mut(&key)
+                && entry.filter.is_none()
+            {
+                entry.filter = Some(topic);
             }
-        };
+        }
+        failed.map_or(Ok(()), Err)
+    }
 
-        let req = Request {
-            params,
-            message,
-            state,
-        };
+    /// 把 `child` 的路由挂载到 `prefix` 下，例如 `nest("devices/{id}", child)` 后，
+    /// 子路由中的 `status/{kind}` 匹配 `devices/{id}/status/{kind}`，
+    /// 前缀与子路由的参数合并后由 `Params<T>` 取得。
+    ///
+    /// 子路由的中间件、默认超时与错误回调只作用于子路由自身的路由，派发方式与解压上限使用当前路由的设置；
+    /// 通过 [`MqttRouter::route`] 声明的路由按组合后的主题过滤器在 `serve` 启动与重连时订阅。
+    /// 子路由中已经用 `subscribe` 订阅的主题不会取消，子路由应使用 `route` 声明。
+    /// 事件路由（[`types::EvtTopic`] 等）不加前缀。
+    ///
+    /// 前缀不能是共享订阅，也不能给 `$` 开头的路由加前缀；
+    /// 任一路由冲突或不合法时不挂载任何路由
+    pub fn nest<P: Into<String>>(&mut self, prefix: P, child: MqttRouter<S>) -> MqttResult {
+        let prefix = prefix.into();
+        self.mount(Some(&prefix), child)
+    }
 
-        let v = matched.value.call(req).await?;
-        Ok(v)
+    /// 合并另一个路由的全部路由，路由路径不变，其余规则同 [`MqttRouter::nest`]
+    pub fn merge(&mut self, other: MqttRouter<S>) -> MqttResult {
+        self.mount(None, other)
     }
-}
 
-fn route_to_topic(route: &str) -> String {
-    let mut result = String::new();
-    let mut in_param = false;
+    fn mount(&mut self, prefix: Option<&str>, child: MqttRouter<S>) -> MqttResult {
+        if let Some(prefix) = prefix
+            && prefix.starts_with("$share/")
+        {
+            return Err(RouterError::InvalidRoute {
+                route: prefix.to_string(),
+                reason: "a nest prefix cannot be a shared subscription",
+            }
+            .into());
+        }
+        let layers = child.layers;
+        let timeout = child.timeout;
+        let on_error = child.on_error;
+        let child = child.table.into_inner().unwrap();
+        let table = self.table.get_mut().unwrap();
 
-    for c in route.chars() {
-        match c {
-            '{' => {
-                in_param = true;
-                result.push('+');
+        // 先检查全部路由，全部通过后再插入
+        let mut routes = Vec::with_capacity(child.routes.len() + child.events.len());
+        let mut filters = HashMap::new();
+        let entries = child.routes.into_iter().map(|(_, entry)| entry);
+        for entry in child.events.into_values().chain(entries) {
+            let (route, subscribed) = match prefix {
+                Some(_) if is_event_route(&entry.route) => (entry.route, entry.filter.is_some()),
+                Some(prefix) => {
+                    if entry.route.starts_with('$') && !entry.route.starts_with("$share/") {
+                        return Err(RouterError::InvalidRoute {
+                            route: entry.route,
+                            reason: "a `$` route cannot be nested under a prefix",
+                        }
+                        .into());
+                    }
+                    (join_route(prefix, &entry.route), false)
+                }
+                None => (entry.route, entry.filter.is_some()),
+            };
+            let spec = parse_route(&route)?;
+            let existing = if is_event_route(&route) {
+                table.events.get(&route)
+            } else {
+                table.routes.get(&spec.filter)
+            };
+            let existing = existing
+                .map(|e| e.route.clone())
+                .or_else(|| filters.get(&spec.filter).cloned());
+            if let Some(existing) = existing {
+                return Err(RouterError::RouteConflict { route, existing }.into());
             }
-            '}' => {
-                in_param = false;
+            filters.insert(spec.filter, route.clone());
+            let mut dispatcher = entry.dispatcher.with_layers(layers.clone());
+            dispatcher.timeout = dispatcher.timeout.or(timeout);
+            dispatcher.on_error = dispatcher.on_error.or_else(|| on_error.clone());
+            routes.push((route, dispatcher, entry.qos, subscribed));
+        }
+        for (route, dispatcher, qos, subscribed) in routes {
+            table.insert(route, dispatcher, qos, subscribed)?;
+        }
+        Ok(())
+    }
+
+    /// 派发消息。[`DispatchMode::All`] 下依次执行所有匹配的路由，
+    /// 有路由失败时返回 [`DispatchErrors`]，只有第一个路由回复请求消息。
+    /// payload 解压失败时不执行任何路由，返回 [`RouterError::Decompress`]
+    pub async fn dispatch(&self, message: MqttMessage, state: S) -> AppResult {
+        let topic = message.callback_router_topic();
+        // 只在查找路由时持有读锁，handler 执行期间可以增删路由
+        let mut routes = self
+            .table
+            .read()
+            .unwrap()
+            .resolve(&message, &topic, self.mode);
+        if routes.is_empty() {
+            log::warn!("dispatch error,topic:{}", topic);
+            return Ok(());
+        }
+        // 有路由匹配才解压，提取器拿到的是原始 payload
+        let message = match message {
+            MqttMessage::Msg(msg) if is_compressed(&msg) => {
+                match self.decompress(msg.clone()).await {
+                    Ok(msg) => MqttMessage::Msg(msg),
+                    Err(error) => {
+                        let message = MqttMessage::Msg(msg);
+                        return Err(self.reject(&routes[0].dispatcher, &message, error).await);
+                    }
+                }
             }
-            '/' if !in_param => {
-                result.push('/');
+            message => message,
+        };
+        // 事件路由与 UnkonwTopic 路由只会有一个
+        if self.mode == DispatchMode::MostSpecific
+            || routes[0].unknown
+            || !matches!(message, MqttMessage::Msg(_))
+        {
+            let Some(route) = routes.pop() else {
+                return Ok(());
+            };
+            let req = Request {
+                params: route.params,
+                message,
+                state,
+                format: None,
+            };
+            let res = self.call(&route.dispatcher, req, true).await;
+            if route.unknown {
+                if let Err(e) = res {
+                    log::warn!("unkonw topic handler error: {}", e);
+                }
+                return Ok(());
             }
-            _ if !in_param => {
-                result.push(c);
+            return res;
+        }
+
+        let total = routes.len();
+        let mut errors = Vec::new();
+        for (i, route) in routes.into_iter().enumerate() {
+            let req = Request {
+                params: route.params,
+                message: message.clone(),
+                state: state.clone(),
+                format: None,
+            };
+            if let Err(e) = self.call(&route.dispatcher, req, i == 0).await {
+                errors.push((route.route, e));
             }
-            _ => {}
         }
+        if errors.is_empty() {
+            return Ok(());
+        }
+        Err(DispatchErrors {
+            topic,
+            total,
+            errors,
+        }
+        .into())
     }
 
-    result
+    /// 启动派发任务，见 [`SharedRouter::serve`]
+    pub fn serve(
+        self,
+        rx: mpsc::Receiver<MqttMessage>,
+        state: S,
+        opts: ServeOptions,
+    ) -> JoinHandle<()> {
+        self.into_shared().serve(rx, state, opts)
+    }
 }
 
-macro_rules! impl_make_dispatcher {
-    (
-        [$($ty:ident),*], $last:ident
-    ) => {
-
-impl<F, S, Fut, $($ty,)* $last> MakeDispatcher<($($ty,)* $last,), S> for F
+/// 可以在多个任务间共享的路由句柄，派发进行中也可以增删路由
+pub struct SharedRouter<S = ()>(Arc<MqttRouter<S>>)
 where
-    F: (Fn($($ty,)* $last) -> Fut) + Send + Sync + 'static,
-    Fut: Future<Output = MqttResult> + Send ,
-    S: Clone + Send + Sync + 'static,
-    $( $ty: FromRequest<S>, )*
-    $last: FromRequest<S>
-{
-    #[allow(non_snake_case)]
-    fn make_dispatcher(func: F) -> Dispatcher<S> {
-        let func = Arc::new(func);
-        let wrap: Box<dyn Fn(Request<S>) -> Pin<Box<dyn Future<Output = MqttResult> + Send>> + Send + Sync> =
-            Box::new(move |request: Request<S>| {
+    S: Clone + Send + Sync;
+
+impl<S: Clone + Send + Sync> Clone for SharedRouter<S> {
+    fn clone(&self) -> Self {
+        SharedRouter(self.0.clone())
+    }
+}
+
+impl<S: Clone + Send + Sync + 'static> SharedRouter<S> {
+    pub async fn subscribe<P, T, F>(&self, path: P, handler: F, qos: QoS) -> MqttResult
+    where
+        P: Into<String>,
+        F: MakeDispatcher<T, S>,
+    {
+        self.0
+            .subscribe_route(path.into(), F::make_dispatcher(handler), qos)
+            .await
+    }
+
+    pub fn add<P, T, F>(&self, path: P, handler: F) -> MqttResult
+    where
+        P: Into<String>,
+        F: MakeDispatcher<T, S>,
+    {
+        self.0
+            .add_route(path.into(), F::make_dispatcher(handler), None)
+    }
+
+    /// 删除路由，路由已经订阅时同时取消订阅
+    pub async fn remove<P: Into<String>>(&self, path: P) -> MqttResult {
+        self.0.remove_route(path.into()).await
+    }
+
+    pub async fn dispatch(&self, message: MqttMessage, state: S) -> AppResult {
+        self.0.dispatch(message, state).await
+    }
+
+    /// 启动派发任务：从 `rx` 接收消息并发执行 handler，最多同时运行 `opts.concurrency` 个，
+    /// 最多积压 `opts.backlog` 条未处理完的消息。
+    /// 收到 [`MqttMessage::EvtClosed`] 后派发该事件、等待进行中的 handler 结束后退出。
+    pub fn serve(
+        &self,
+        mut rx: mpsc::Receiver<MqttMessage>,
+        state: S,
+        opts: ServeOptions,
+    ) -> JoinHandle<()> {
+        let router = self.0.clone();
+        let concurrency = opts.concurrency.max(1);
+        let limit = Arc::new(Semaphore::new(concurrency));
+        let backlog = Arc::new(Semaphore::new(opts.backlog.max(concurrency)));
+        let chains = Arc::new(Mutex::new(OrderChains::default()));
+
+        tokio::spawn(async move {
+            if let Err(e) = router.subscribe_pending().await {
+                log::error!("subscribe routes error: {}", e);
+            }
+            let mut tasks = JoinSet::new();
+            while let Some(message) = rx.recv().await {
+                let closed = message == MqttMessage::EvtClosed;
+                // 断开期间订阅失败的路由在重连后补上
+                if message == MqttMessage::EvtConnected
+                    && let Err(e) = router.subscribe_pending().await
+                {
+                    log::error!("subscribe routes error: {}", e);
+                }
+                let Ok(queued) = backlog.clone().acquire_owned().await else {
+                    break;
+                };
+
+                let link = opts
+                    .order
+                    .key(&message)
+                    .map(|key| OrderChains::push(&chains, key));
+                let router = router.clone();
+                let chains = chains.clone();
+                let state = state.clone();
+                let limit = limit.clone();
+                tasks.spawn(async move {
+                    let _queued = queued;
+                    let mut link = link;
+                    if let Some(prev) = link.as_mut().and_then(|l| l.prev.take()) {
+                        prev.await.ok();
+                    }
+                    // 等到轮到自己再占并发名额，避免同一主题排队的消息占满名额阻塞其他主题
+                    let Ok(_permit) = limit.acquire_owned().await else {
+                        return;
+                    };
+
+                    if let Err(e) = router.dispatch(message, state).await {
+                        log::error!("dispatch error: {}", e);
+                    }
+
+                    if let Some(link) = link {
+                        OrderChains::finish(&chains, link);
+                    }
+                });
+
+                while let Some(res) = tasks.try_join_next() {
+                    if let Err(e) = res {
+                        log::error!("dispatch task error: {}", e);
+                    }
+                }
+                if closed {
+                    break;
+                }
+            }
+
+            while let Some(res) = tasks.join_next().await {
+                if let Err(e) = res {
+                    log::error!("dispatch task error: {}", e);
+                }
+            }
+            log::info!("mqtt router serve close...");
+        })
+    }
+}
+
+// 路由表：按主题过滤器索引的路由。同一主题过滤器只能有一个路由，
+// 删除路由时直接取消它订阅的主题过滤器
+struct RouteTable<S>
+where
+    S: Clone + Send + Sync,
+{
+    // 主题过滤器（不含共享订阅前缀）-> 路由
+    routes: TopicTrie<RouteEntry<S>>,
+    // 事件路由（EvtTopic 等）-> 路由，只精确匹配，不参与通配符匹配
+    events: HashMap<String, RouteEntry<S>>,
+}
+
+// 保留添加时的信息，挂载到其他路由时据此重新生成
+struct RouteEntry<S>
+where
+    S: Clone + Send + Sync,
+{
+    route: String,
+    dispatcher: Dispatcher<S>,
+    // 通配符对应的参数名
+    params: Vec<String>,
+    // 需要订阅时的 QoS，`add` 添加的路由为 None
+    qos: Option<QoS>,
+    // 已经订阅的主题过滤器
+    filter: Option<String>,
+}
+
+struct ResolvedRoute<S>
+where
+    S: Clone + Send + Sync,
+{
+    route: String,
+    dispatcher: Dispatcher<S>,
+    params: JsonValue,
+    // 是否为 UnkonwTopic 路由
+    unknown: bool,
+}
+
+impl<S: Clone + Send + Sync + 'static> RouteTable<S> {
+    fn new() -> Self {
+        RouteTable {
+            routes: TopicTrie::new(),
+            events: HashMap::new(),
+        }
+    }
+
+    fn insert(
+        &mut self,
+        route: String,
+        dispatcher: Dispatcher<S>,
+        qos: Option<QoS>,
+        subscribed: bool,
+    ) -> MqttResult<RouteSpec> {
+        let spec = parse_route(&route)?;
+        let existing = if is_event_route(&route) {
+            self.events.get(&route)
+        } else {
+            self.routes.get(&spec.filter)
+        };
+        if let Some(existing) = existing {
+            return Err(RouterError::RouteConflict {
+                route,
+                existing: existing.route.clone(),
+            }
+            .into());
+        }
+        let filter = subscribed.then(|| spec.topic.clone());
+        let entry = RouteEntry {
+            route,
+            dispatcher,
+            params: spec.params.clone(),
+            qos,
+            filter,
+        };
+        if is_event_route(&entry.route) {
+            self.events.insert(entry.route.clone(), entry);
+        } else {
+            self.routes.insert(&spec.filter, entry)?;
+        }
+        Ok(spec)
+    }
+
+    // 返回被删除的路由已经订阅、需要取消订阅的主题过滤器
+    fn remove(&mut self, route: &str) -> MqttResult<Option<String>> {
+        let spec = parse_route(route)?;
+        let entry = if is_event_route(route) {
+            self.events.remove(route)
+        } else {
+            self.routes.remove(&spec.filter)
+        };
+        Ok(entry.and_then(|e| e.filter))
+    }
+
+    // 声明了 QoS 但还没有订阅的路由：(路由表中的主题过滤器, 订阅的主题过滤器, QoS)
+    fn pending(&self) -> Vec<(String, String, QoS)> {
+        self.routes
+            .iter()
+            .filter(|(_, e)| e.filter.is_none())
+            .filter_map(|(key, e)| {
+                let qos = e.qos?;
+                let topic = parse_route(&e.route).ok()?.topic;
+                Some((key.to_string(), topic, qos))
+            })
+            .collect()
+    }
+
+    // `MostSpecific` 时使用最具体的路由：逐级比较，字面量优先于 `+`，`+` 优先于 `#`；
+    // `All` 时按添加顺序返回所有匹配的路由。没有匹配的路由时使用 UnkonwTopic 路由。
+    // 事件与主题不是 UTF-8 的消息只查找对应的事件路由
+    fn resolve(
+        &self,
+        message: &MqttMessage,
+        topic: &str,
+        mode: DispatchMode,
+    ) -> Vec<ResolvedRoute<S>> {
+        let event = match message {
+            MqttMessage::Msg(msg) => std::str::from_utf8(&msg.topic).is_err(),
+            _ => true,
+        };
+        if event {
+            return self
+                .events
+                .get(topic)
+                .map(|entry| ResolvedRoute {
+                    route: entry.route.clone(),
+                    dispatcher: entry.dispatcher.clone(),
+                    params: JsonValue::Null,
+                    unknown: topic == types::UnkonwTopic,
+                })
+                .into_iter()
+                .collect();
+        }
+
+        let mut matched = self.routes.matching(topic);
+        if mode == DispatchMode::MostSpecific {
+            matched = matched
+                .into_iter()
+                .max_by(|a, b| specificity(a.filter.filter()).cmp(specificity(b.filter.filter())))
+                .into_iter()
+                .collect();
+        }
+        if matched.is_empty() {
+            let Some(entry) = self.events.get(types::UnkonwTopic) else {
+                return Vec::new();
+            };
+            return vec![ResolvedRoute {
+                route: entry.route.clone(),
+                dispatcher: entry.dispatcher.clone(),
+                params: JsonValue::Null,
+                unknown: true,
+            }];
+        }
+
+        matched
+            .into_iter()
+            .map(|matched| {
+                let entry = matched.value;
+                let params: serde_json::Map<String, JsonValue> = entry
+                    .params
+                    .iter()
+                    .zip(matched.captures.iter())
+                    .filter(|(name, _)| !name.is_empty())
+                    .map(|(name, value)| (name.clone(), JsonValue::from(*value)))
+                    .collect();
+                let params = if params.is_empty() {
+                    JsonValue::Null
+                } else {
+                    JsonValue::Object(params)
+                };
+                ResolvedRoute {
+                    route: entry.route.clone(),
+                    dispatcher: entry.dispatcher.clone(),
+                    params,
+                    unknown: false,
+                }
+            })
+            .collect()
+    }
+}
+
+// 各级的优先级，主题过滤器已经结束（精确匹配父级）时最高
+fn specificity(filter: &str) -> impl Iterator<Item = u8> + '_ {
+    filter
+        .split('/')
+        .map(|level| match level {
+            "#" => 0,
+            "+" => 1,
+            _ => 2,
+        })
+        .chain(std::iter::once(3))
+}
+
+// 路由解析结果：路由表使用的主题过滤器（不含共享订阅前缀）、订阅使用的主题过滤器，
+// 以及各通配符对应的参数名
+pub(crate) struct RouteSpec {
+    filter: String,
+    topic: String,
+    params: Vec<String>,
+}
+
+pub(crate) const CATCH_ALL_PARAM: &str = "rest";
+
+pub(crate) fn parse_route(route: &str) -> Result<RouteSpec, RouterError> {
+    let invalid = |reason| RouterError::InvalidRoute {
+        route: route.to_string(),
+        reason,
+    };
+    // 共享订阅 `$share/{group}/{filter}`：订阅时保留前缀，broker 投递的消息主题不带前缀，
+    // 路由按 filter 匹配
+    let (share, filter) = match route.strip_prefix("$share/") {
+        Some(rest) => {
+            let (group, filter) = rest
+                .split_once('/')
+                .ok_or_else(|| invalid("a shared subscription needs a topic filter"))?;
+            if group.is_empty() || group.contains(['+', '#', '{', '}']) {
+                return Err(invalid("invalid shared subscription group"));
+            }
+            (Some(group), filter)
+        }
+        None => (None, route),
+    };
+
+    let levels: Vec<&str> = filter.split('/').collect();
+    let mut topic = Vec::with_capacity(levels.len());
+    let mut params = Vec::new();
+
+    for (i, level) in levels.iter().enumerate() {
+        let is_last = i + 1 == levels.len();
+        let name = if *level == "#" {
+            Some(CATCH_ALL_PARAM)
+        } else {
+            level.strip_prefix("{*").and_then(|s| s.strip_suffix('}'))
+        };
+        if let Some(name) = name {
+            if !is_last {
+                return Err(invalid("`#` must be the last topic level"));
+            }
+            topic.push("#".to_string());
+            params.push(name.to_string());
+            continue;
+        }
+
+        let is_param = level.starts_with('{')
+            && level.ends_with('}')
+            && level[1..level.len() - 1]
+                .chars()
+                .all(|c| c != '{' && c != '}');
+        if is_param {
+            topic.push("+".to_string());
+            params.push(level[1..level.len() - 1].to_string());
+        } else if *level == "+" {
+            // 没有名字的参数，匹配但不提取
+            topic.push("+".to_string());
+            params.push(String::new());
+        } else if level.contains(['{', '}']) {
+            return Err(invalid("a parameter must occupy a whole topic level"));
+        } else if level.contains('#') {
+            return Err(invalid("`#` must occupy a whole topic level"));
+        } else {
+            topic.push(level.to_string());
+        }
+    }
+
+    let filter = topic.join("/");
+    let topic = match share {
+        Some(group) => format!("$share/{group}/{filter}"),
+        None => filter.clone(),
+    };
+    validate_topic_filter(&topic)?;
+    Ok(RouteSpec {
+        filter,
+        topic,
+        params,
+    })
+}
+
+fn is_event_route(route: &str) -> bool {
+    [types::EvtTopic, types::EvtErrorTopic, types::UnkonwTopic].contains(&route)
+}
+
+// 共享订阅的前缀保留在最外层：`$share/g/a` 挂载到 `p` 下为 `$share/g/p/a`
+fn join_route(prefix: &str, route: &str) -> String {
+    let prefix = prefix.trim_end_matches('/');
+    let (share, route) = match route
+        .strip_prefix("$share/")
+        .and_then(|rest| rest.split_once('/'))
+    {
+        Some((group, filter)) => (format!("$share/{group}/"), filter),
+        None => (String::new(), route),
+    };
+    let route = route.trim_start_matches('/');
+    if route.is_empty() {
+        format!("{share}{prefix}")
+    } else {
+        format!("{share}{prefix}/{route}")
+    }
+}
+
+impl<F, S, Fut, R> MakeDispatcher<(), S> for F
+where
+    F: (Fn() -> Fut) + Send + Sync + 'static,
+    Fut: Future<Output = R> + Send,
+    R: IntoResponse,
+    S: Clone + Send + Sync + 'static,
+{
+    fn make_dispatcher(func: F) -> Dispatcher<S> {
+        let func = Arc::new(func);
+        let wrap: Box<dyn Fn(Request<S>) -> BoxFuture<MqttResult<Response>> + Send + Sync> =
+            Box::new(move |_request: Request<S>| {
+                let func = func.clone();
+                Box::pin(async move { func().await.into_response() })
+            });
+
+        Dispatcher::new(wrap)
+    }
+}
+
+macro_rules! impl_make_dispatcher {
+    (
+        $([$ty:ident, $m:ident]),*
+    ) => {
+
+impl<F, S, Fut, R, $($ty, $m,)*> MakeDispatcher<($(($m, $ty),)*), S> for F
+where
+    F: (Fn($($ty,)*) -> Fut) + Send + Sync + 'static,
+    Fut: Future<Output = R> + Send,
+    R: IntoResponse,
+    S: Clone + Send + Sync + 'static,
+    $( $ty: FromRequestAsync<S, $m> + Send, )*
+{
+    #[allow(non_snake_case)]
+    fn make_dispatcher(func: F) -> Dispatcher<S> {
+        let func = Arc::new(func);
+        let wrap: Box<dyn Fn(Request<S>) -> BoxFuture<MqttResult<Response>> + Send + Sync> =
+            Box::new(move |request: Request<S>| {
                 let func = func.clone();
                 Box::pin(async move {
                     $(
-                    let $ty = $ty::from_request(&request)?;
+                    let $ty = <$ty as FromRequestAsync<S, $m>>::from_request_async(&request)
+                        .await
+                        .map_err(rejected)?;
                     )*
 
-                    let $last = $last::from_request(&request)?;
-
-                    func($($ty,)* $last).await
+                    func($($ty,)*).await.into_response()
                 })
             });
 
@@ -277,41 +1494,1110 @@ where
 #[rustfmt::skip]
 macro_rules! all_the_tuples {
     ($name:ident) => {
-        $name!([], T1);
-        $name!([T1], T2);
-        $name!([T1, T2], T3); 
+        $name!([T1, M1]);
+        $name!([T1, M1], [T2, M2]);
+        $name!([T1, M1], [T2, M2], [T3, M3]);
+        $name!([T1, M1], [T2, M2], [T3, M3], [T4, M4]);
+        $name!([T1, M1], [T2, M2], [T3, M3], [T4, M4], [T5, M5]);
+        $name!([T1, M1], [T2, M2], [T3, M3], [T4, M4], [T5, M5], [T6, M6]);
+        $name!([T1, M1], [T2, M2], [T3, M3], [T4, M4], [T5, M5], [T6, M6], [T7, M7]);
+        $name!([T1, M1], [T2, M2], [T3, M3], [T4, M4], [T5, M5], [T6, M6], [T7, M7], [T8, M8]);
+        $name!([T1, M1], [T2, M2], [T3, M3], [T4, M4], [T5, M5], [T6, M6], [T7, M7], [T8, M8], [T9, M9]);
+        $name!([T1, M1], [T2, M2], [T3, M3], [T4, M4], [T5, M5], [T6, M6], [T7, M7], [T8, M8], [T9, M9], [T10, M10]);
+        $name!([T1, M1], [T2, M2], [T3, M3], [T4, M4], [T5, M5], [T6, M6], [T7, M7], [T8, M8], [T9, M9], [T10, M10], [T11, M11]);
+        $name!([T1, M1], [T2, M2], [T3, M3], [T4, M4], [T5, M5], [T6, M6], [T7, M7], [T8, M8], [T9, M9], [T10, M10], [T11, M11], [T12, M12]);
+        $name!([T1, M1], [T2, M2], [T3, M3], [T4, M4], [T5, M5], [T6, M6], [T7, M7], [T8, M8], [T9, M9], [T10, M10], [T11, M11], [T12, M12], [T13, M13]);
+        $name!([T1, M1], [T2, M2], [T3, M3], [T4, M4], [T5, M5], [T6, M6], [T7, M7], [T8, M8], [T9, M9], [T10, M10], [T11, M11], [T12, M12], [T13, M13], [T14, M14]);
+        $name!([T1, M1], [T2, M2], [T3, M3], [T4, M4], [T5, M5], [T6, M6], [T7, M7], [T8, M8], [T9, M9], [T10, M10], [T11, M11], [T12, M12], [T13, M13], [T14, M14], [T15, M15]);
+        $name!([T1, M1], [T2, M2], [T3, M3], [T4, M4], [T5, M5], [T6, M6], [T7, M7], [T8, M8], [T9, M9], [T10, M10], [T11, M11], [T12, M12], [T13, M13], [T14, M14], [T15, M15], [T16, M16]);
     };
 }
 
 all_the_tuples!(impl_make_dispatcher);
 
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
     use super::*;
+    use crate::{Client, Config, Message, State, Topic, UserProperties};
+    use rumqttc::v5::mqttbytes::v5::PublishProperties;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tokio::sync::watch;
+
+    pub(crate) fn test_client() -> MqttClient {
+        test_client_with_loop().0
+    }
+
+    // 保留 EventLoop，通过 `clean` 取出客户端发出的报文
+    pub(crate) fn test_client_with_loop() -> (MqttClient, rumqttc::v5::EventLoop) {
+        let (_, state) = watch::channel(State::Connected);
+        let (close, _) = watch::channel(false);
+        let (mqtt, eventloop) =
+            rumqttc::v5::AsyncClient::new(Config::new("test", "127.0.0.1", 1883), 10);
+        (Arc::new(Client::new(state, mqtt, close)), eventloop)
+    }
+
+    // 与 `start_with_cfg` 创建的客户端一样共享 Rpc 与别名表，`cap` 为请求通道容量
+    pub(crate) fn shared_client_with_loop(
+        rpc: Arc<crate::Rpc>,
+        aliases: Arc<crate::OutgoingAliases>,
+        cap: usize,
+    ) -> (MqttClient, rumqttc::v5::EventLoop) {
+        let (_, state) = watch::channel(State::Connected);
+        let (close, _) = watch::channel(false);
+        let (mqtt, eventloop) =
+            rumqttc::v5::AsyncClient::new(Config::new("test", "127.0.0.1", 1883), cap);
+        let client = Client::with_shared(state, mqtt, close, rpc, aliases);
+        (Arc::new(client), eventloop)
+    }
+
+    pub(crate) fn sent(eventloop: &mut rumqttc::v5::EventLoop) -> Vec<Message> {
+        eventloop.clean();
+        eventloop
+            .pending
+            .drain(..)
+            .filter_map(|req| match req {
+                rumqttc::v5::Request::Publish(msg) => Some(msg),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub(crate) fn publish(topic: &str, payload: &'static str) -> MqttMessage {
+        MqttMessage::Msg(Message::new(topic, QoS::AtMostOnce, payload, None))
+    }
+
+    #[derive(Clone, Default)]
+    struct Counter {
+        running: Arc<AtomicUsize>,
+        peak: Arc<AtomicUsize>,
+        seen: Arc<Mutex<Vec<String>>>,
+    }
+
+    async fn slow_handler(
+        Payload(payload): Payload<String>,
+        StateHandle(counter): StateHandle<Counter>,
+    ) -> MqttResult {
+        let running = counter.running.fetch_add(1, Ordering::SeqCst) + 1;
+        counter.peak.fetch_max(running, Ordering::SeqCst);
+        let delay: u64 = payload.parse().unwrap_or(0);
+        tokio::time::sleep(Duration::from_millis(delay)).await;
+        counter.seen.lock().unwrap().push(payload);
+        counter.running.fetch_sub(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn serve_limits_concurrency_and_stops_on_closed() -> MqttResult {
+        let mut router = MqttRouter::<Counter>::new(test_client());
+        router.add("jobs/{id}", slow_handler)?;
+
+        let counter = Counter::default();
+        let (tx, rx) = mpsc::channel(16);
+        let opts = ServeOptions {
+            concurrency: 2,
+            ..ServeOptions::default()
+        };
+        let handle = router.serve(rx, counter.clone(), opts);
+        for id in 0..6 {
+            tx.send(publish(&format!("jobs/{id}"), "20")).await?;
+        }
+        tx.send(MqttMessage::EvtClosed).await?;
+
+        tokio::time::timeout(Duration::from_secs(5), handle).await??;
+        assert_eq!(counter.seen.lock().unwrap().len(), 6);
+        assert_eq!(counter.peak.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn serve_keeps_per_topic_order() -> MqttResult {
+        let mut router = MqttRouter::<Counter>::new(test_client());
+        router.add("jobs/{id}", slow_handler)?;
+
+        let counter = Counter::default();
+        let (tx, rx) = mpsc::channel(16);
+        let opts = ServeOptions {
+            concurrency: 4,
+            order: DispatchOrder::PerTopic,
+            ..ServeOptions::default()
+        };
+        let handle = router.serve(rx, counter.clone(), opts);
+        for delay in ["30", "20", "10", "0"] {
+            tx.send(publish("jobs/1", delay)).await?;
+        }
+        drop(tx);
+
+        tokio::time::timeout(Duration::from_secs(5), handle).await??;
+        assert_eq!(*counter.seen.lock().unwrap(), ["30", "20", "10", "0"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn serve_hot_topic_does_not_block_others() -> MqttResult {
+        let mut router = MqttRouter::<Counter>::new(test_client());
+        router.add("jobs/{id}", slow_handler)?;
+
+        let counter = Counter::default();
+        let (tx, rx) = mpsc::channel(16);
+        let opts = ServeOptions {
+            concurrency: 2,
+            order: DispatchOrder::PerTopic,
+            ..ServeOptions::default()
+        };
+        let handle = router.serve(rx, counter.clone(), opts);
+        for _ in 0..6 {
+            tx.send(publish("jobs/1", "50")).await?;
+        }
+        tx.send(publish("jobs/2", "0")).await?;
+        drop(tx);
+
+        tokio::time::timeout(Duration::from_secs(5), handle).await??;
+        let seen = counter.seen.lock().unwrap();
+        assert_eq!(seen.len(), 7);
+        // jobs/2 不必等 jobs/1 排队的消息，在 jobs/1 第一条处理完之前就已完成
+        assert_eq!(seen[0], "0");
+        assert_eq!(counter.peak.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    async fn panic_handler(Payload(payload): Payload<String>) -> MqttResult {
+        panic!("bad payload {payload}");
+    }
+
+    #[tokio::test]
+    async fn dispatch_reports_timeout_and_panic() -> MqttResult {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let mut router = MqttRouter::<Counter>::new(test_client());
+        router.add("jobs/{id}", slow_handler)?;
+        router.add(
+            "slow/{id}",
+            handler(slow_handler).with_timeout(Duration::from_millis(500)),
+        )?;
+        router.add("panic/{id}", panic_handler)?;
+        let seen = reports.clone();
+        router
+            .default_timeout(Duration::from_millis(10))
+            .on_error(move |report| {
+                seen.lock()
+                    .unwrap()
+                    .push((report.topic.to_string(), report.error.to_string()));
+            });
+
+        let err = router
+            .dispatch(publish("jobs/1", "200"), Counter::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<RouterError>(),
+            Some(RouterError::HandlerTimeout { .. })
+        ));
+
+        router
+            .dispatch(publish("slow/1", "50"), Counter::default())
+            .await?;
+
+        let err = router
+            .dispatch(publish("panic/1", "x"), Counter::default())
+            .await
+            .unwrap_err();
+        match err.downcast_ref::<RouterError>() {
+            Some(RouterError::HandlerPanicked { topic, message }) => {
+                assert_eq!(topic, "panic/1");
+                assert_eq!(message, "bad payload x");
+            }
+            other => panic!("unexpected error {other:?}"),
+        }
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].0, "jobs/1");
+        assert_eq!(reports[1].0, "panic/1");
+        Ok(())
+    }
+
+    #[derive(serde::Deserialize, Serialize, Debug, PartialEq)]
+    struct Reading {
+        temp: f32,
+    }
 
     #[test]
-    fn test_route_to_topic() {
+    fn json_extract_and_reject() -> MqttResult {
+        let req = Request::new(publish("dev/1", r#"{"temp":21.5}"#), ());
+        let Json(reading) = Json::<Reading>::from_request(&req)?;
+        assert_eq!(reading, Reading { temp: 21.5 });
+        assert_eq!(Json(reading).to_bytes()?, r#"{"temp":21.5}"#);
+
+        let req = Request::new(publish("dev/1", "not json"), ());
+        match Json::<Reading>::from_request(&req) {
+            Err(RouterError::JsonRejected { topic, excerpt, .. }) => {
+                assert_eq!(topic, "dev/1");
+                assert_eq!(excerpt, "not json");
+            }
+            _ => panic!("expected json rejection"),
+        }
+        Ok(())
+    }
+
+    async fn lenient(
+        reading: Option<Json<Reading>>,
+        checked: Result<Json<Reading>, RouterError>,
+        StateHandle(seen): StateHandle<Arc<Mutex<Vec<String>>>>,
+    ) -> MqttResult {
+        let mut seen = seen.lock().unwrap();
+        seen.push(format!("{:?}", reading.map(|Json(r)| r.temp)));
+        match checked {
+            Ok(Json(r)) => seen.push(format!("ok {}", r.temp)),
+            Err(e) => seen.push(format!(
+                "rejected {}",
+                matches!(e, RouterError::JsonRejected { .. })
+            )),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn optional_and_fallible_extractors() -> MqttResult {
+        let mut router = MqttRouter::new(test_client());
+        router.add("dev/{id}", lenient)?;
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        router
+            .dispatch(publish("dev/1", r#"{"temp":1.5}"#), seen.clone())
+            .await?;
+        router
+            .dispatch(publish("dev/1", "oops"), seen.clone())
+            .await?;
+        assert_eq!(
+            *seen.lock().unwrap(),
+            ["Some(1.5)", "ok 1.5", "None", "rejected true"]
+        );
+        Ok(())
+    }
+
+    type Registry = Arc<tokio::sync::RwLock<HashMap<String, String>>>;
+
+    struct Device(String);
+
+    impl FromRequestAsync<Registry> for Device {
+        type Rejection = anyhow::Error;
+
+        async fn from_request_async(req: &Request<Registry>) -> MqttResult<Self> {
+            let id = req.params()["id"].as_str().unwrap_or_default().to_string();
+            tokio::task::yield_now().await;
+            let devices = req.state().read().await;
+            let name = devices
+                .get(&id)
+                .ok_or_else(|| anyhow::anyhow!("unknown device {id}"))?;
+            Ok(Device(name.clone()))
+        }
+    }
+
+    async fn device_handler(
+        Device(name): Device,
+        fallback: Option<Device>,
+        Payload(payload): Payload<String>,
+    ) -> MqttResult {
+        assert!(fallback.is_some());
+        anyhow::ensure!(payload == name, "{payload} != {name}");
+        Ok(())
+    }
+
+    async fn maybe_device(
+        device: Option<Device>,
+        StateHandle(reg): StateHandle<Registry>,
+    ) -> MqttResult {
+        let name = device.map(|Device(n)| n).unwrap_or_else(|| "none".into());
+        reg.write().await.insert("seen".into(), name);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn async_extractors() -> MqttResult {
+        let mut router = MqttRouter::new(test_client());
+        router.add("dev/{id}", device_handler)?;
+        router.add("maybe/{id}", maybe_device)?;
+
+        let registry = Registry::default();
+        registry.write().await.insert("1".into(), "pump".into());
+        router
+            .dispatch(publish("dev/1", "pump"), registry.clone())
+            .await?;
+        assert!(
+            router
+                .dispatch(publish("dev/2", "pump"), registry.clone())
+                .await
+                .is_err()
+        );
+
+        router
+            .dispatch(publish("maybe/2", ""), registry.clone())
+            .await?;
+        assert_eq!(registry.read().await["seen"], "none");
+        Ok(())
+    }
+
+    async fn binary_fallback(
+        RawPayload(raw): RawPayload,
+        Utf8Lossy(text): Utf8Lossy,
+        StateHandle(seen): StateHandle<Arc<Mutex<Vec<String>>>>,
+    ) -> MqttResult {
+        assert_eq!(raw.len(), 3);
+        seen.lock().unwrap().push(text);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unknown_topic_fallback_accepts_binary() -> MqttResult {
+        let mut router = MqttRouter::new(test_client());
+        router.add(types::UnkonwTopic, binary_fallback)?;
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let payload = Bytes::from_static(&[0x66, 0x77, 0xff]);
+        let message = MqttMessage::Msg(Message::new("fw/chunk", QoS::AtMostOnce, payload, None));
+        let req = Request::new(message.clone(), ());
+        let RawPayload(copy) = RawPayload::<Vec<u8>>::from_request(&req)?;
+        assert_eq!(copy, [0x66, 0x77, 0xff]);
+
+        router.dispatch(message, seen.clone()).await?;
+        assert_eq!(*seen.lock().unwrap(), ["fw\u{fffd}"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn event_routes_are_not_wildcard_matched() -> MqttResult {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut router = MqttRouter::new(test_client());
+        router.add("#", tag_topic)?;
+        router.add("+/r_rmqttc/#", tag_topic)?;
+
+        router
+            .dispatch(MqttMessage::EvtConnected, seen.clone())
+            .await?;
+        router
+            .dispatch(MqttMessage::EvtError("x".into()), seen.clone())
+            .await?;
+        assert!(seen.lock().unwrap().is_empty());
+
+        // 主题不是 UTF-8 的消息交给 UnkonwTopic 路由，错误只记录日志
+        router.add(types::UnkonwTopic, reject_topic)?;
+        let mut message = Message::new("", QoS::AtMostOnce, "", None);
+        message.topic = Bytes::from_static(&[0x66, 0xff]);
+        router
+            .dispatch(MqttMessage::Msg(message), seen.clone())
+            .await?;
+
+        router.add(types::EvtTopic, tag_topic)?;
+        router
+            .dispatch(MqttMessage::EvtConnected, seen.clone())
+            .await?;
+        assert_eq!(*seen.lock().unwrap(), [format!("{} null", types::EvtTopic)]);
+        Ok(())
+    }
+
+    async fn no_args() -> MqttResult {
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn sixteen_args(
+        Topic(a): Topic,
+        Payload(b): Payload<String>,
+        Utf8Lossy(c): Utf8Lossy,
+        RawPayload(d): RawPayload,
+        Params(e): Params<JsonValue>,
+        UserProperties(f): UserProperties,
+        Topic(g): Topic,
+        Topic(h): Topic,
+        Topic(i): Topic,
+        Topic(j): Topic,
+        Topic(k): Topic,
+        Topic(l): Topic,
+        Topic(m): Topic,
+        Topic(n): Topic,
+        Topic(o): Topic,
+        StateHandle(seen): StateHandle<Arc<Mutex<Vec<String>>>>,
+    ) -> MqttResult {
+        assert_eq!(b, c);
+        assert_eq!(d, b.as_bytes());
+        assert!(f.is_empty());
+        let mut seen = seen.lock().unwrap();
+        seen.extend([a, g, h, i, j, k, l, m, n, o]);
+        seen.push(e["id"].to_string());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handlers_with_zero_to_sixteen_args() -> MqttResult {
+        let mut router = MqttRouter::new(test_client());
+        router.add("none", no_args)?;
+        router.add("many/{id}", sixteen_args)?;
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        router.dispatch(publish("none", "x"), seen.clone()).await?;
+        router
+            .dispatch(publish("many/7", "x"), seen.clone())
+            .await?;
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 11);
+        assert_eq!(seen[0], "many/7");
+        assert_eq!(seen[10], "\"7\"");
+        Ok(())
+    }
+
+    #[test]
+    fn test_route_to_topic() -> MqttResult {
         for (route, expected_topic) in [
             ("hello/{there}", "hello/+"),
             ("a/{b}/foo", "a/+/foo"),
             ("hello", "hello"),
+            ("devices/{id}/logs/#", "devices/+/logs/#"),
+            ("devices/{id}/logs/{*path}", "devices/+/logs/#"),
+            ("#", "#"),
+            ("$share/workers/jobs/{id}", "$share/workers/jobs/+"),
         ] {
-            let topic = route_to_topic(route);
+            let topic = parse_route(route)?.topic;
             assert_eq!(
                 topic, expected_topic,
                 "route={route}, expected={expected_topic} actual={topic}"
             );
         }
+
+        let spec = parse_route("devices/{id}/logs/#")?;
+        assert_eq!(spec.filter, "devices/+/logs/#");
+        assert_eq!(spec.params, ["id", "rest"]);
+
+        let spec = parse_route("$share/workers/jobs/{id}")?;
+        assert_eq!(spec.filter, "jobs/+");
+
+        for route in [
+            "a/#/b",
+            "a/{*rest}/b",
+            "a/b#",
+            "a/x{id}",
+            "a/{id}x",
+            "$share/workers",
+            "$share//jobs",
+            "$share/{group}/jobs",
+        ] {
+            assert!(
+                matches!(parse_route(route), Err(RouterError::InvalidRoute { .. })),
+                "route={route}"
+            );
+        }
+        Ok(())
     }
 
-    #[test]
-    fn routing() -> MqttResult {
-        let mut router = Router::new();
-        router.insert("pv2mqtt/home", "Welcome!")?;
-        router.insert("pv2mqtt/users/{name}/{id}", "A User")?;
-        let matched = router.at("pv2mqtt/users/foo/978")?;
-        assert_eq!(matched.params.get("id"), Some("978"));
-        assert_eq!(*matched.value, "A User");
+    #[derive(serde::Deserialize)]
+    struct LogParams {
+        id: String,
+        #[serde(deserialize_with = "crate::deserialize_segments")]
+        rest: Vec<String>,
+    }
+
+    async fn logs(
+        Params(LogParams { id, rest }): Params<LogParams>,
+        StateHandle(seen): StateHandle<Arc<Mutex<Vec<String>>>>,
+    ) -> MqttResult {
+        seen.lock().unwrap().push(format!("{id}:{rest:?}"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn multi_level_wildcard_route() -> MqttResult {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut router = MqttRouter::new(test_client());
+        router.add("devices/{id}/logs/#", logs)?;
+
+        for topic in ["devices/1/logs/a/b", "devices/2/logs", "devices/3/logs/x"] {
+            router.dispatch(publish(topic, ""), seen.clone()).await?;
+        }
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [r#"1:["a", "b"]"#, "2:[]", r#"3:["x"]"#]
+        );
+
+        router.remove("devices/{id}/logs/#").await?;
+        let table = router.table.read().unwrap();
+        assert!(
+            table
+                .resolve(
+                    &publish("devices/2/logs", ""),
+                    "devices/2/logs",
+                    DispatchMode::MostSpecific
+                )
+                .is_empty()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shared_subscription_route() -> MqttResult {
+        let (client, mut eventloop) = test_client_with_loop();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut router = MqttRouter::new(client);
+        router
+            .subscribe("$share/workers/jobs/{id}", record_job, QoS::AtLeastOnce)
+            .await?;
+        router
+            .dispatch(publish("jobs/123", ""), seen.clone())
+            .await?;
+        assert_eq!(*seen.lock().unwrap(), ["123"]);
+
+        eventloop.clean();
+        let filters: Vec<_> = eventloop
+            .pending
+            .drain(..)
+            .filter_map(|req| match req {
+                rumqttc::v5::Request::Subscribe(sub) => Some(sub.filters[0].path.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(filters, ["$share/workers/jobs/+"]);
+        Ok(())
+    }
+
+    async fn record_job(
+        Params(id): Params<HashMap<String, String>>,
+        StateHandle(seen): StateHandle<Arc<Mutex<Vec<String>>>>,
+    ) -> MqttResult {
+        seen.lock().unwrap().push(id["id"].clone());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shared_router_changes_routes_while_serving() -> MqttResult {
+        let (client, mut eventloop) = test_client_with_loop();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let router = MqttRouter::new(client).into_shared();
+        let (tx, rx) = mpsc::channel(8);
+        let handle = router.serve(rx, seen.clone(), ServeOptions::default());
+
+        tx.send(publish("jobs/1", "")).await?;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        router
+            .subscribe("jobs/{id}", record_job, QoS::AtLeastOnce)
+            .await?;
+        tx.send(publish("jobs/2", "")).await?;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        router.remove("jobs/{id}").await?;
+        tx.send(publish("jobs/3", "")).await?;
+        tx.send(MqttMessage::EvtClosed).await?;
+        handle.await?;
+        assert_eq!(*seen.lock().unwrap(), ["2"]);
+
+        eventloop.clean();
+        let requests: Vec<_> = eventloop
+            .pending
+            .drain(..)
+            .filter_map(|req| match req {
+                rumqttc::v5::Request::Subscribe(sub) => {
+                    Some(format!("sub {}", sub.filters[0].path))
+                }
+                rumqttc::v5::Request::Unsubscribe(unsub) => {
+                    Some(format!("unsub {}", unsub.filters[0]))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(requests, ["sub jobs/+", "unsub jobs/+"]);
+        Ok(())
+    }
+
+    #[derive(serde::Deserialize)]
+    struct StatusParams {
+        id: String,
+        kind: String,
+    }
+
+    async fn device_status(
+        Params(StatusParams { id, kind }): Params<StatusParams>,
+        StateHandle(seen): StateHandle<Arc<Mutex<Vec<String>>>>,
+    ) -> MqttResult {
+        seen.lock().unwrap().push(format!("{id}:{kind}"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn nest_and_merge_routers() -> MqttResult {
+        let (client, mut eventloop) = test_client_with_loop();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+
+        let mut devices = MqttRouter::new(client.clone());
+        devices.route("status/{kind}", device_status, QoS::AtLeastOnce)?;
+        devices.layer(
+            |req: Request<Arc<Mutex<Vec<String>>>>, next: Next<Arc<Mutex<Vec<String>>>>| async move {
+                req.state().lock().unwrap().push("devices".into());
+                next.run(req).await
+            },
+        );
+        let mut jobs = MqttRouter::new(client.clone());
+        jobs.route("$share/workers/jobs/{id}", record_job, QoS::AtMostOnce)?;
+
+        let mut router = MqttRouter::new(client);
+        router.nest("devices/{id}", devices)?;
+        router.merge(jobs)?;
+        router.subscribe_pending().await?;
+        // 已订阅的路由不会重复订阅
+        router.subscribe_pending().await?;
+
+        router
+            .dispatch(publish("devices/7/status/temp", ""), seen.clone())
+            .await?;
+        router.dispatch(publish("jobs/9", ""), seen.clone()).await?;
+        assert_eq!(*seen.lock().unwrap(), ["devices", "7:temp", "9"]);
+
+        eventloop.clean();
+        let mut filters: Vec<_> = eventloop
+            .pending
+            .drain(..)
+            .filter_map(|req| match req {
+                rumqttc::v5::Request::Subscribe(sub) => Some(sub.filters[0].path.clone()),
+                _ => None,
+            })
+            .collect();
+        filters.sort();
+        assert_eq!(filters, ["$share/workers/jobs/+", "devices/+/status/+"]);
+
+        assert_eq!(join_route("a/", "/b"), "a/b");
+        assert_eq!(join_route("a", ""), "a");
+        assert_eq!(join_route("a", "$share/g/b"), "$share/g/a/b");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn subscribe_pending_after_reconnect() -> MqttResult {
+        let (state_tx, state) = watch::channel(State::Disconnected);
+        let (close, _) = watch::channel(false);
+        let (mqtt, mut eventloop) =
+            rumqttc::v5::AsyncClient::new(Config::new("test", "127.0.0.1", 1883), 10);
+        let mut router = MqttRouter::new(Arc::new(Client::new(state, mqtt, close)));
+        router.route("devices/{id}/status", no_args, QoS::AtLeastOnce)?;
+        router.route("jobs/{id}", no_args, QoS::AtMostOnce)?;
+        // 未连接时每个路由都会尝试订阅
+        assert!(router.subscribe_pending().await.is_err());
+
+        let (tx, rx) = mpsc::channel(4);
+        let handle = router.serve(rx, (), ServeOptions::default());
+        state_tx.send(State::Connected)?;
+        tx.send(MqttMessage::EvtConnected).await?;
+        tx.send(MqttMessage::EvtClosed).await?;
+        tokio::time::timeout(Duration::from_secs(5), handle).await??;
+
+        eventloop.clean();
+        let mut filters: Vec<_> = eventloop
+            .pending
+            .drain(..)
+            .filter_map(|req| match req {
+                rumqttc::v5::Request::Subscribe(sub) => Some(sub.filters[0].path.clone()),
+                _ => None,
+            })
+            .collect();
+        filters.sort();
+        assert_eq!(filters, ["devices/+/status", "jobs/+"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn nest_checks_all_routes_first() -> MqttResult {
+        async fn noop() {}
+
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let hook = |name: &'static str| {
+            let reports = reports.clone();
+            move |report: &DispatchReport<'_>| {
+                reports
+                    .lock()
+                    .unwrap()
+                    .push(format!("{name} {}", report.topic));
+            }
+        };
+        let mut router = MqttRouter::new(test_client());
+        router.add("devices/{id}/status/{kind}", noop)?;
+        router.add("panic/{id}", panic_handler)?;
+        router.on_error(hook("parent"));
+
+        let child = || -> MqttResult<MqttRouter> {
+            let mut child = MqttRouter::new(test_client());
+            child.add("ping", noop)?;
+            child.add("status/{kind}", noop)?;
+            child.add("crash", panic_handler)?;
+            child.on_error(hook("child"));
+            Ok(child)
+        };
+        // 后面的路由冲突时前面的也不挂载
+        let err = router.nest("devices/{id}", child()?).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<RouterError>(),
+            Some(RouterError::RouteConflict { .. })
+        ));
+        let routes = |router: &MqttRouter| router.table.read().unwrap().routes.len();
+        assert_eq!(routes(&router), 2);
+
+        for prefix in ["$share/g/devices", "a/#"] {
+            let err = router.nest(prefix, child()?).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<RouterError>(),
+                Some(RouterError::InvalidRoute { .. })
+            ));
+        }
+        let mut sys = MqttRouter::new(test_client());
+        sys.add("$SYS/uptime", noop)?;
+        assert!(router.nest("devices", sys).is_err());
+        assert_eq!(routes(&router), 2);
+
+        // 子路由的错误回调只作用于子路由自身的路由
+        router.nest("gw/{id}", child()?)?;
+        assert_eq!(routes(&router), 5);
+        assert!(
+            router
+                .dispatch(publish("gw/1/crash", ""), ())
+                .await
+                .is_err()
+        );
+        assert!(router.dispatch(publish("panic/1", ""), ()).await.is_err());
+        assert_eq!(
+            *reports.lock().unwrap(),
+            ["child gw/1/crash", "parent panic/1"]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn topic_template_as_route() -> MqttResult {
+        let template = crate::TopicTemplate::parse("jobs/{id}")?;
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut router = MqttRouter::new(test_client());
+        router.add(&template, record_job)?;
+
+        let topic = template.render(&HashMap::from([("id", 5)]))?;
+        router.dispatch(publish(&topic, ""), seen.clone()).await?;
+        assert_eq!(*seen.lock().unwrap(), ["5"]);
+        Ok(())
+    }
+
+    async fn tag_topic(
+        Topic(topic): Topic,
+        Params(params): Params<JsonValue>,
+        StateHandle(seen): StateHandle<Arc<Mutex<Vec<String>>>>,
+    ) -> MqttResult {
+        seen.lock().unwrap().push(format!("{topic} {params}"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn overlapping_wildcard_routes() -> MqttResult {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut router = MqttRouter::new(test_client());
+        router.add("a/{x}/c", tag_topic)?;
+        router.add("a/b/{y}", tag_topic)?;
+        router.add("a/#", tag_topic)?;
+        router.add("a", tag_topic)?;
+        assert!(router.add("$share/g/a/{z}/c", tag_topic).is_err());
+
+        for topic in ["a/b/c", "a/x/c", "a/b/x", "a/x/y", "a"] {
+            router.dispatch(publish(topic, ""), seen.clone()).await?;
+        }
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [
+                r#"a/b/c {"y":"c"}"#,
+                r#"a/x/c {"x":"x"}"#,
+                r#"a/b/x {"y":"x"}"#,
+                r#"a/x/y {"rest":"x/y"}"#,
+                "a null",
+            ]
+        );
+        Ok(())
+    }
+
+    async fn reject_topic(Topic(topic): Topic) -> MqttResult {
+        Err(anyhow::anyhow!("reject {topic}"))
+    }
+
+    #[tokio::test]
+    async fn dispatch_to_all_matching_routes() -> MqttResult {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut router = MqttRouter::new(test_client());
+        router.dispatch_mode(DispatchMode::All);
+        router.add("#", tag_topic)?;
+        router.add("dev/{id}/temp", tag_topic)?;
+        router.add("dev/+/#", reject_topic)?;
+        router.add(types::UnkonwTopic, tag_topic)?;
+
+        let err = router
+            .dispatch(publish("dev/1/temp", ""), seen.clone())
+            .await
+            .unwrap_err();
+        let err = err.downcast_ref::<DispatchErrors>().unwrap();
+        assert_eq!((err.total, err.errors.len()), (3, 1));
+        assert_eq!(err.errors[0].0, "dev/+/#");
+
+        router.dispatch(publish("other", ""), seen.clone()).await?;
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [
+                r#"dev/1/temp {"rest":"dev/1/temp"}"#,
+                r#"dev/1/temp {"id":"1"}"#,
+                r#"other {"rest":"other"}"#,
+            ]
+        );
+
+        // `#` 上的审计路由收不到连接事件
+        router
+            .dispatch(MqttMessage::EvtConnected, seen.clone())
+            .await?;
+        assert_eq!(seen.lock().unwrap().len(), 3);
+
+        // 只匹配一个路由时错误同样包装为 DispatchErrors
+        let mut router = MqttRouter::new(test_client());
+        router.dispatch_mode(DispatchMode::All);
+        router.add("alarm/{id}", reject_topic)?;
+        let err = router
+            .dispatch(publish("alarm/1", ""), seen.clone())
+            .await
+            .unwrap_err();
+        let err = err.downcast_ref::<DispatchErrors>().unwrap();
+        assert_eq!((err.total, err.errors.len()), (1, 1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dispatch_all_replies_once() -> MqttResult {
+        let (client, mut eventloop) = test_client_with_loop();
+        let mut router = MqttRouter::new(client);
+        router.dispatch_mode(DispatchMode::All);
+        router.add("rpc/{name}", status)?;
+        router.add("rpc/echo", echo)?;
+
+        router.dispatch(request("rpc/echo", "1"), ()).await?;
+        let sent = sent(&mut eventloop);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].payload, "rpc/echo");
+        Ok(())
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[tokio::test]
+    async fn route_format() -> MqttResult {
+        use crate::{Codec, Encoded};
+
+        async fn reading(
+            Encoded(value): Encoded<HashMap<String, u32>>,
+            StateHandle(seen): StateHandle<Arc<Mutex<Vec<String>>>>,
+        ) -> MqttResult {
+            seen.lock().unwrap().push(format!("{}", value["temp"]));
+            Ok(())
+        }
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut router = MqttRouter::new(test_client());
+        router.add("dev/temp", handler(reading).with_format(Format::MsgPack))?;
+        let payload = Format::MsgPack.encode(&HashMap::from([("temp".to_string(), 21u32)]))?;
+        let msg = crate::Message::new("dev/temp", QoS::AtMostOnce, payload, None);
+        router.dispatch(MqttMessage::Msg(msg), seen.clone()).await?;
+        assert_eq!(*seen.lock().unwrap(), ["21"]);
+        Ok(())
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[tokio::test]
+    async fn encoded_reply_uses_request_format() -> MqttResult {
+        use crate::{Codec, Encoded, MSGPACK_CONTENT_TYPE};
+
+        async fn double(Encoded(value): Encoded<Vec<u32>>) -> Encoded<Vec<u32>> {
+            Encoded(value.into_iter().map(|v| v * 2).collect())
+        }
+
+        let (client, mut eventloop) = test_client_with_loop();
+        let mut router = MqttRouter::new(client);
+        router.add("calc/double", double)?;
+        router.add("calc/raw", handler(double).with_format(Format::MsgPack))?;
+
+        let payload = Format::MsgPack.encode(&vec![1u32, 2])?;
+        let mut with_content_type = request("calc/double", "");
+        let mut without = request("calc/raw", "");
+        for (msg, content_type) in [
+            (&mut with_content_type, Some(MSGPACK_CONTENT_TYPE)),
+            (&mut without, None),
+        ] {
+            let MqttMessage::Msg(msg) = msg else {
+                unreachable!()
+            };
+            msg.payload = payload.clone();
+            msg.properties.as_mut().unwrap().content_type = content_type.map(Into::into);
+        }
+        router.dispatch(with_content_type, ()).await?;
+        router.dispatch(without, ()).await?;
+        router.dispatch(request("calc/double", "[3]"), ()).await?;
+
+        let sent = sent(&mut eventloop);
+        assert_eq!(sent.len(), 3);
+        for msg in &sent[..2] {
+            let props = msg.properties.as_ref().unwrap();
+            assert_eq!(props.content_type.as_deref(), Some(MSGPACK_CONTENT_TYPE));
+            let value: Vec<u32> = Format::MsgPack.decode(&msg.payload)?;
+            assert_eq!(value, [2, 4]);
+        }
+        assert_eq!(sent[2].payload, "[6]");
+        Ok(())
+    }
+
+    #[cfg(feature = "gzip")]
+    #[tokio::test]
+    async fn decompress_before_extract() -> MqttResult {
+        use crate::{CONTENT_ENCODING, CompressError, Compression};
+
+        type Seen = Arc<Mutex<Vec<String>>>;
+
+        async fn batch(
+            Json(logs): Json<Vec<String>>,
+            StateHandle(seen): StateHandle<Seen>,
+        ) -> MqttResult {
+            seen.lock().unwrap().extend(logs);
+            Ok(())
+        }
+
+        async fn raw(RawPayload(payload): RawPayload, StateHandle(seen): StateHandle<Seen>) {
+            seen.lock()
+                .unwrap()
+                .push(String::from_utf8_lossy(&payload).into_owned());
+        }
+
+        async fn count(Payload(n): Payload<u32>, StateHandle(seen): StateHandle<Seen>) {
+            seen.lock().unwrap().push(n.to_string());
+        }
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let (client, mut eventloop) = test_client_with_loop();
+        let mut router = MqttRouter::new(client);
+        router.max_decompressed_size(64);
+        router.add("logs", batch)?;
+        router.add("raw", raw)?;
+        router.add("count", count)?;
+        let hook_reports = reports.clone();
+        router.on_error(move |report| {
+            hook_reports.lock().unwrap().push(report.topic.to_string());
+        });
+
+        let compressed = |topic: &str, payload: &str, rpc: bool| -> MqttResult<MqttMessage> {
+            let mut properties = PublishProperties {
+                user_properties: vec![(CONTENT_ENCODING.into(), "gzip".into())],
+                ..PublishProperties::default()
+            };
+            if rpc {
+                properties.response_topic = Some("reply/1".into());
+                properties.correlation_data = Some(Bytes::from_static(b"c1"));
+            }
+            let payload = Compression::Gzip.compress(payload.as_bytes())?;
+            let msg = crate::Message::new(topic, QoS::AtMostOnce, payload, Some(properties));
+            Ok(MqttMessage::Msg(msg))
+        };
+        router
+            .dispatch(compressed("logs", r#"["a","b"]"#, false)?, seen.clone())
+            .await?;
+        router
+            .dispatch(compressed("raw", "hello", false)?, seen.clone())
+            .await?;
+        router
+            .dispatch(compressed("count", "42", false)?, seen.clone())
+            .await?;
+        assert_eq!(*seen.lock().unwrap(), ["a", "b", "hello", "42"]);
+
+        // 没有路由匹配时不解压
+        let big = serde_json::to_string(&vec!["x"; 100])?;
+        router
+            .dispatch(compressed("other", &big, false)?, seen.clone())
+            .await?;
+        assert!(reports.lock().unwrap().is_empty());
+
+        // 解压失败时回复错误响应并通知错误回调
+        let err = router
+            .dispatch(compressed("logs", &big, true)?, seen.clone())
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<Rejected>().is_some());
+        assert!(matches!(
+            err.downcast_ref::<RouterError>(),
+            Some(RouterError::Decompress(CompressError::TooLarge {
+                limit: 64
+            }))
+        ));
+        assert_eq!(*reports.lock().unwrap(), ["logs"]);
+        assert_eq!(seen.lock().unwrap().len(), 4);
+
+        let sent = sent(&mut eventloop);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].topic, "reply/1");
+        let props = sent[0].properties.as_ref().unwrap();
+        assert_eq!(props.correlation_data.as_deref(), Some(&b"c1"[..]));
+        Ok(())
+    }
+
+    async fn echo(Json(value): Json<serde_json::Value>) -> Json<serde_json::Value> {
+        Json(serde_json::json!({ "echo": value }))
+    }
+
+    async fn status(Topic(topic): Topic) -> (String, UserProperties) {
+        let props = UserProperties(vec![("code".into(), "200".into())]);
+        (topic, props)
+    }
+
+    fn request(topic: &str, payload: &'static str) -> MqttMessage {
+        let properties = PublishProperties {
+            response_topic: Some("reply/1".into()),
+            correlation_data: Some(Bytes::from_static(b"c1")),
+            ..PublishProperties::default()
+        };
+        MqttMessage::Msg(Message::new(
+            topic,
+            QoS::AtLeastOnce,
+            payload,
+            Some(properties),
+        ))
+    }
+
+    #[tokio::test]
+    async fn handler_response_published_to_response_topic() -> MqttResult {
+        let (client, mut eventloop) = test_client_with_loop();
+        let mut router = MqttRouter::new(client);
+        router.add("rpc/echo", echo)?;
+        router.add("rpc/status", status)?;
+
+        router
+            .dispatch(request("rpc/echo", r#"{"a":1}"#), ())
+            .await?;
+        router.dispatch(request("rpc/status", ""), ()).await?;
+        assert!(
+            router
+                .dispatch(request("rpc/echo", "oops"), ())
+                .await
+                .is_err()
+        );
+        // 没有 response topic 时不回复
+        router.dispatch(publish("rpc/echo", "1"), ()).await?;
+
+        let sent = sent(&mut eventloop);
+        assert_eq!(sent.len(), 3);
+        for msg in &sent {
+            assert_eq!(msg.topic, "reply/1");
+            assert_eq!(msg.qos, QoS::AtLeastOnce);
+            let props = msg.properties.as_ref().unwrap();
+            assert_eq!(props.correlation_data.as_deref(), Some(&b"c1"[..]));
+        }
+
+        assert_eq!(sent[0].payload, r#"{"echo":{"a":1}}"#);
+        let props = sent[0].properties.as_ref().unwrap();
+        assert_eq!(props.content_type.as_deref(), Some("application/json"));
+
+        assert_eq!(sent[1].payload, "rpc/status");
+        let props = sent[1].properties.as_ref().unwrap();
+        assert_eq!(props.user_properties, [("code".into(), "200".into())]);
+
+        let props = sent[2].properties.as_ref().unwrap();
+        assert_eq!(props.user_properties, [("status".into(), "error".into())]);
+        assert!(String::from_utf8_lossy(&sent[2].payload).starts_with("request rejected"));
         Ok(())
     }
 }
@@ -0,0 +1,188 @@
+use crate::Message;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
+
+type Pending = Arc<Mutex<HashMap<Bytes, oneshot::Sender<Message>>>>;
+
+/// 请求/响应共享的状态，`Client` 发起请求，`Manager` 在消息进入路由通道之前
+/// 按 correlation data 匹配回复
+#[derive(Debug)]
+pub(crate) struct Rpc {
+    client_id: String,
+    // 本次运行的标识，避免与上一次运行遗留的回复混淆
+    nonce: String,
+    seq: AtomicU64,
+    // ConnAck 中的 response information
+    response_information: Mutex<Option<String>>,
+    // 已订阅的回复主题
+    subscribed: Mutex<Option<String>>,
+    pending: Pending,
+}
+
+impl Rpc {
+    pub(crate) fn new(client_id: impl Into<String>) -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        let nonce = format!("{:x}", nanos as u64);
+        let mut client_id = client_id.into();
+        if client_id.is_empty() {
+            client_id = nonce.clone();
+        }
+        Rpc {
+            client_id,
+            nonce,
+            seq: AtomicU64::new(0),
+            response_information: Mutex::new(None),
+            subscribed: Mutex::new(None),
+            pending: Pending::default(),
+        }
+    }
+
+    /// 连接成功后记录 broker 提供的 response information
+    pub(crate) fn set_response_information(&self, info: Option<String>) {
+        *self
+            .response_information
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = info.filter(|s| !s.is_empty());
+    }
+
+    /// 回复主题：broker 提供 response information 时为 `{info}/{client_id}`，
+    /// 否则为 `rmqttc/reply/{client_id}`
+    pub(crate) fn reply_topic(&self) -> String {
+        match &*self
+            .response_information
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+        {
+            Some(info) => format!("{}/{}", info.trim_end_matches('/'), self.client_id),
+            None => format!("rmqttc/reply/{}", self.client_id),
+        }
+    }
+
+    pub(crate) fn subscribed(&self) -> Option<String> {
+        self.subscribed
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub(crate) fn set_subscribed(&self, topic: Option<String>) {
+        *self.subscribed.lock().unwrap_or_else(|e| e.into_inner()) = topic;
+    }
+
+    /// 登记一个等待中的请求，返回的 [`PendingReply`] 被丢弃时（超时或取消）自动移除登记
+    pub(crate) fn register(&self) -> PendingReply {
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        let correlation = Bytes::from(format!("{}-{}", self.nonce, seq));
+        let (tx, rx) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(correlation.clone(), tx);
+        PendingReply {
+            correlation,
+            rx,
+            pending: self.pending.clone(),
+        }
+    }
+
+    /// 处理收到的消息：是等待中请求的回复时交给请求方；发到回复主题但已经没有
+    /// 对应请求的迟到回复直接丢弃；其他消息原样返回
+    pub(crate) fn resolve(&self, msg: Message) -> Option<Message> {
+        let Some(reply_topic) = self.subscribed() else {
+            return Some(msg);
+        };
+        if msg.topic != reply_topic.as_bytes() {
+            return Some(msg);
+        }
+        let tx = msg
+            .properties
+            .as_ref()
+            .and_then(|p| p.correlation_data.as_ref())
+            .and_then(|c| {
+                self.pending
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .remove(c)
+            });
+        match tx {
+            Some(tx) => {
+                tx.send(msg).ok();
+            }
+            None => log::debug!("drop late or unknown reply on {}", reply_topic),
+        }
+        None
+    }
+
+    #[cfg(test)]
+    pub(crate) fn pending_len(&self) -> usize {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+}
+
+pub(crate) struct PendingReply {
+    pub(crate) correlation: Bytes,
+    pub(crate) rx: oneshot::Receiver<Message>,
+    pending: Pending,
+}
+
+impl Drop for PendingReply {
+    fn drop(&mut self) {
+        self.pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.correlation);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::QoS;
+    use rumqttc::v5::mqttbytes::v5::PublishProperties;
+
+    fn reply(topic: &str, correlation: Bytes) -> Message {
+        let properties = PublishProperties {
+            correlation_data: Some(correlation),
+            ..PublishProperties::default()
+        };
+        Message::new(topic, QoS::AtLeastOnce, "pong", Some(properties))
+    }
+
+    #[tokio::test]
+    async fn match_reply_by_correlation() {
+        let rpc = Rpc::new("dev1");
+        assert_eq!(rpc.reply_topic(), "rmqttc/reply/dev1");
+        rpc.set_response_information(Some("resp/".into()));
+        assert_eq!(rpc.reply_topic(), "resp/dev1");
+        rpc.set_subscribed(Some(rpc.reply_topic()));
+
+        let mut first = rpc.register();
+        let second = rpc.register();
+        assert_ne!(first.correlation, second.correlation);
+        assert_eq!(rpc.pending_len(), 2);
+
+        // 非回复主题的消息交给路由
+        assert!(
+            rpc.resolve(reply("dev/1", first.correlation.clone()))
+                .is_some()
+        );
+        assert!(
+            rpc.resolve(reply("resp/dev1", first.correlation.clone()))
+                .is_none()
+        );
+        assert_eq!((&mut first.rx).await.unwrap().payload, "pong");
+
+        // 超时后登记被移除，迟到的回复被丢弃
+        let correlation = second.correlation.clone();
+        drop(second);
+        assert_eq!(rpc.pending_len(), 0);
+        assert!(rpc.resolve(reply("resp/dev1", correlation)).is_none());
+    }
+}
@@ -0,0 +1,176 @@
+use crate::{
+    BoxFuture, Dispatcher, HandlerFn, IntoResponse, MqttResult, MqttRouter, Request, Response,
+    SharedRouter,
+};
+use std::error::Error as StdError;
+use std::future::poll_fn;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower_service::Service;
+
+type BoxError = Box<dyn StdError + Send + Sync>;
+
+impl<S> Service<Request<S>> for Dispatcher<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    type Response = Response;
+    type Error = anyhow::Error;
+    type Future = BoxFuture<MqttResult<Response>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<MqttResult> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<S>) -> Self::Future {
+        let this = self.clone();
+        Box::pin(async move { Dispatcher::call(&this, req).await })
+    }
+}
+
+impl<S> Service<Request<S>> for Arc<MqttRouter<S>>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    type Response = ();
+    type Error = anyhow::Error;
+    type Future = BoxFuture<MqttResult>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<MqttResult> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<S>) -> Self::Future {
+        let router = self.clone();
+        Box::pin(async move {
+            let (message, state) = req.into_parts();
+            router.dispatch(message, state).await
+        })
+    }
+}
+
+// 与 `Arc<MqttRouter>` 不同，运行期间通过 `SharedRouter` 增删的路由对 tower 调用方同样可见
+impl<S> Service<Request<S>> for SharedRouter<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    type Response = ();
+    type Error = anyhow::Error;
+    type Future = BoxFuture<MqttResult>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<MqttResult> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<S>) -> Self::Future {
+        let router = self.clone();
+        Box::pin(async move {
+            let (message, state) = req.into_parts();
+            router.dispatch(message, state).await
+        })
+    }
+}
+
+impl<S> Dispatcher<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    /// 把任意 tower `Service` 注册为路由，每条消息都会克隆一次 service 并等待其就绪：
+    /// `router.add("jobs/{id}", Dispatcher::from_service(ServiceBuilder::new().timeout(d).service(svc)))`
+    pub fn from_service<T>(service: T) -> Self
+    where
+        T: Service<Request<S>> + Clone + Send + Sync + 'static,
+        T::Response: IntoResponse,
+        T::Error: Into<BoxError>,
+        T::Future: Send + 'static,
+    {
+        let func: HandlerFn<S> = Arc::new(move |req: Request<S>| {
+            let mut service = service.clone();
+            Box::pin(async move {
+                poll_fn(|cx| service.poll_ready(cx))
+                    .await
+                    .map_err(into_anyhow)?;
+                service
+                    .call(req)
+                    .await
+                    .map_err(into_anyhow)?
+                    .into_response()
+            })
+        });
+        Dispatcher {
+            func,
+            timeout: None,
+            format: None,
+            on_error: None,
+        }
+    }
+}
+
+fn into_anyhow<E: Into<BoxError>>(e: E) -> anyhow::Error {
+    anyhow::Error::from_boxed(e.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::router::test::{publish, test_client};
+    use crate::{Payload, StateHandle, handler};
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tower::{ServiceBuilder, ServiceExt};
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    async fn record(
+        Payload(payload): Payload<String>,
+        StateHandle(log): StateHandle<Log>,
+    ) -> MqttResult {
+        if payload == "slow" {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+        log.lock().unwrap().push(payload);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tower_service_as_route() -> MqttResult {
+        let service = ServiceBuilder::new()
+            .timeout(Duration::from_millis(50))
+            .service(handler(record));
+        let mut router = MqttRouter::<Log>::new(test_client());
+        router.add("a/{id}", Dispatcher::from_service(service))?;
+        let router = Arc::new(router);
+
+        let log = Log::default();
+        router
+            .clone()
+            .oneshot(Request::new(publish("a/1", "fast"), log.clone()))
+            .await?;
+        let err = router
+            .oneshot(Request::new(publish("a/1", "slow"), log.clone()))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "request timed out");
+        assert_eq!(*log.lock().unwrap(), ["fast"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shared_router_as_service() -> MqttResult {
+        let router = MqttRouter::<Log>::new(test_client()).into_shared();
+        let log = Log::default();
+        router
+            .clone()
+            .oneshot(Request::new(publish("b/1", "before"), log.clone()))
+            .await?;
+
+        // 运行期间添加的路由对 tower 调用方可见
+        router.add("b/{id}", record)?;
+        router
+            .clone()
+            .oneshot(Request::new(publish("b/1", "after"), log.clone()))
+            .await?;
+        assert_eq!(*log.lock().unwrap(), ["after"]);
+        Ok(())
+    }
+}
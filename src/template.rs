@@ -0,0 +1,378 @@
+use crate::RouterError;
+use serde::de::value::{Error as ValueError, MapDeserializer};
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use serde::{Serialize, forward_to_deserialize_any};
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum TemplateError {
+    #[error(transparent)]
+    InvalidTemplate(#[from] RouterError),
+    #[error("topic template value {0} is missing")]
+    MissingValue(String),
+    #[error("topic template value {name}={value:?} must not contain `/`, `+` or `#`")]
+    InvalidValue { name: String, value: String },
+    #[error("topic template values must be a struct or map: {0}")]
+    NotAnObject(String),
+    #[error("topic {topic} does not match template {template}")]
+    Mismatch { topic: String, template: String },
+    #[error("failed to parse topic {topic}: {source}")]
+    Deserialize { topic: String, source: ValueError },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Level {
+    Literal(String),
+    Param(String),
+    // 多级通配，只能位于最后一级
+    CatchAll(String),
+}
+
+/// 主题模板，例如 `tenants/{tenant}/devices/{id}/cmd`，解析一次后可以重复使用：
+/// 渲染发布的主题、作为路由注册，或者把收到的主题解析为结构体。
+///
+/// 语法与路由相同：`{name}` 占据一整级，最后一级的 `#` 或 `{*name}` 匹配多级。
+/// 共享订阅前缀 `$share/{group}/` 只用于订阅，渲染和解析的主题不带该前缀
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicTemplate {
+    raw: String,
+    // 共享订阅前缀 `$share/{group}/`
+    share: Option<String>,
+    levels: Vec<Level>,
+}
+
+impl TopicTemplate {
+    pub fn parse(template: &str) -> Result<Self, TemplateError> {
+        // 与路由使用同一套校验
+        crate::router::parse_route(template)?;
+        let (share, topic) = match template
+            .strip_prefix("$share/")
+            .and_then(|rest| rest.split_once('/'))
+        {
+            Some((group, topic)) => (Some(format!("$share/{group}/")), topic),
+            None => (None, template),
+        };
+        let levels = topic
+            .split('/')
+            .map(|level| {
+                if level == "#" {
+                    return Level::CatchAll(crate::router::CATCH_ALL_PARAM.to_string());
+                }
+                if let Some(name) = level.strip_prefix("{*").and_then(|s| s.strip_suffix('}')) {
+                    return Level::CatchAll(name.to_string());
+                }
+                match level.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                    Some(name) => Level::Param(name.to_string()),
+                    None => Level::Literal(level.to_string()),
+                }
+            })
+            .collect();
+        Ok(TopicTemplate {
+            raw: template.to_string(),
+            share,
+            levels,
+        })
+    }
+
+    /// 模板原文，可以直接用作路由：`router.subscribe(template.as_str(), ..)`
+    pub fn as_str(&self) -> &str {
+        &self.raw
+    }
+
+    /// 模板中的参数名
+    pub fn params(&self) -> impl Iterator<Item = &str> {
+        self.levels.iter().filter_map(|level| match level {
+            Level::Param(name) | Level::CatchAll(name) => Some(name.as_str()),
+            Level::Literal(_) => None,
+        })
+    }
+
+    /// 订阅使用的主题过滤器，参数替换为 `+`，多级通配替换为 `#`，保留共享订阅前缀
+    pub fn filter(&self) -> String {
+        let filter = self
+            .levels
+            .iter()
+            .map(|level| match level {
+                Level::Literal(s) => s.as_str(),
+                Level::Param(_) => "+",
+                Level::CatchAll(_) => "#",
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+        format!("{}{filter}", self.share.as_deref().unwrap_or_default())
+    }
+
+    /// 用结构体或 map 的字段渲染主题，字段值可以是字符串、数字或布尔值，
+    /// 值中不能包含 `/`、`+`、`#`（多级通配参数允许 `/`）
+    pub fn render<T: Serialize + ?Sized>(&self, values: &T) -> Result<String, TemplateError> {
+        let values = match serde_json::to_value(values) {
+            Ok(JsonValue::Object(map)) => map,
+            Ok(other) => return Err(TemplateError::NotAnObject(other.to_string())),
+            Err(e) => return Err(TemplateError::NotAnObject(e.to_string())),
+        };
+        let value = |name: &str, allow_slash: bool| -> Result<String, TemplateError> {
+            let value = match values.get(name) {
+                Some(JsonValue::String(s)) => s.clone(),
+                Some(v @ (JsonValue::Number(_) | JsonValue::Bool(_))) => v.to_string(),
+                _ => return Err(TemplateError::MissingValue(name.to_string())),
+            };
+            let forbidden = |c: char| c == '+' || c == '#' || (c == '/' && !allow_slash);
+            if value.contains(forbidden) {
+                return Err(TemplateError::InvalidValue {
+                    name: name.to_string(),
+                    value,
+                });
+            }
+            Ok(value)
+        };
+
+        let mut topic = Vec::with_capacity(self.levels.len());
+        for level in &self.levels {
+            match level {
+                Level::Literal(s) => topic.push(s.clone()),
+                Level::Param(name) => topic.push(value(name, false)?),
+                Level::CatchAll(name) => {
+                    let rest = value(name, true)?;
+                    if !rest.is_empty() {
+                        topic.push(rest);
+                    }
+                }
+            }
+        }
+        Ok(topic.join("/"))
+    }
+
+    /// 按模板匹配主题并取出各参数的值，多级通配参数的值为剩余的各级（可能为空）
+    pub fn captures(&self, topic: &str) -> Option<HashMap<String, String>> {
+        let mut parts = topic.split('/');
+        let mut captures = HashMap::new();
+        for level in &self.levels {
+            match level {
+                Level::Literal(s) => {
+                    if parts.next()? != s {
+                        return None;
+                    }
+                }
+                Level::Param(name) => {
+                    captures.insert(name.clone(), parts.next()?.to_string());
+                }
+                Level::CatchAll(name) => {
+                    let rest: Vec<&str> = parts.by_ref().collect();
+                    captures.insert(name.clone(), rest.join("/"));
+                }
+            }
+        }
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(captures)
+    }
+
+    /// 把主题解析为结构体，字段可以是字符串、数字、布尔值或单元枚举
+    pub fn parse_topic<T: DeserializeOwned>(&self, topic: &str) -> Result<T, TemplateError> {
+        let captures = self
+            .captures(topic)
+            .ok_or_else(|| TemplateError::Mismatch {
+                topic: topic.to_string(),
+                template: self.raw.clone(),
+            })?;
+        let map = MapDeserializer::new(
+            captures
+                .iter()
+                .map(|(k, v)| (k.as_str(), LevelDeserializer(v.as_str()))),
+        );
+        T::deserialize(map).map_err(|source| TemplateError::Deserialize {
+            topic: topic.to_string(),
+            source,
+        })
+    }
+}
+
+impl FromStr for TopicTemplate {
+    type Err = TemplateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        TopicTemplate::parse(s)
+    }
+}
+
+impl Display for TopicTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.raw)
+    }
+}
+
+impl From<&TopicTemplate> for String {
+    fn from(template: &TopicTemplate) -> Self {
+        template.raw.clone()
+    }
+}
+
+impl From<TopicTemplate> for String {
+    fn from(template: TopicTemplate) -> Self {
+        template.raw
+    }
+}
+
+// 主题中的一级，按目标类型解析字符串
+struct LevelDeserializer<'a>(&'a str);
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                match self.0.parse() {
+                    Ok(v) => visitor.$visit(v),
+                    Err(_) => Err(de::Error::invalid_value(de::Unexpected::Str(self.0), &visitor)),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for LevelDeserializer<'_> {
+    type Error = ValueError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_str(self.0)
+    }
+
+    deserialize_parsed! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_enum(self.0.into_deserializer())
+    }
+
+    forward_to_deserialize_any! {
+        char str string bytes byte_buf unit unit_struct seq tuple tuple_struct
+        map struct identifier ignored_any
+    }
+}
+
+impl<'de, 'a> IntoDeserializer<'de, ValueError> for LevelDeserializer<'a> {
+    type Deserializer = LevelDeserializer<'a>;
+
+    fn into_deserializer(self) -> Self::Deserializer {
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct DeviceTopic {
+        tenant: String,
+        id: u32,
+    }
+
+    #[test]
+    fn render_and_parse_topic() -> Result<(), TemplateError> {
+        let template: TopicTemplate = "tenants/{tenant}/devices/{id}/cmd".parse()?;
+        assert_eq!(template.filter(), "tenants/+/devices/+/cmd");
+        assert_eq!(template.params().collect::<Vec<_>>(), ["tenant", "id"]);
+
+        let device = DeviceTopic {
+            tenant: "acme".into(),
+            id: 42,
+        };
+        let topic = template.render(&device)?;
+        assert_eq!(topic, "tenants/acme/devices/42/cmd");
+        assert_eq!(template.parse_topic::<DeviceTopic>(&topic)?, device);
+
+        let values = HashMap::from([("tenant", "acme"), ("id", "x")]);
+        assert_eq!(template.render(&values)?, "tenants/acme/devices/x/cmd");
+
+        assert!(matches!(
+            template.render(&HashMap::from([("tenant", "a/b"), ("id", "1")])),
+            Err(TemplateError::InvalidValue { .. })
+        ));
+        assert!(matches!(
+            template.render(&HashMap::from([("tenant", "a")])),
+            Err(TemplateError::MissingValue(_))
+        ));
+        assert!(matches!(
+            template.parse_topic::<DeviceTopic>("tenants/acme/devices/x/cmd"),
+            Err(TemplateError::Deserialize { .. })
+        ));
+        assert!(matches!(
+            template.parse_topic::<DeviceTopic>("tenants/acme/devices/1"),
+            Err(TemplateError::Mismatch { .. })
+        ));
+        assert!(TopicTemplate::parse("a/#/b").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn catch_all_template() -> Result<(), TemplateError> {
+        let template = TopicTemplate::parse("logs/{id}/#")?;
+        assert_eq!(template.filter(), "logs/+/#");
+        let values = HashMap::from([("id", "1"), ("rest", "a/b")]);
+        assert_eq!(template.render(&values)?, "logs/1/a/b");
+        let captures = template.captures("logs/1").unwrap();
+        assert_eq!(captures["rest"], "");
+        assert_eq!(template.captures("logs/1/a/b").unwrap()["rest"], "a/b");
+        Ok(())
+    }
+
+    #[test]
+    fn shared_template() -> Result<(), TemplateError> {
+        let template = TopicTemplate::parse("$share/workers/tenants/{tenant}/devices/{id}")?;
+        assert_eq!(
+            template.as_str(),
+            "$share/workers/tenants/{tenant}/devices/{id}"
+        );
+        assert_eq!(template.filter(), "$share/workers/tenants/+/devices/+");
+        assert_eq!(template.params().collect::<Vec<_>>(), ["tenant", "id"]);
+
+        // 收到的消息主题不带共享订阅前缀
+        let device = DeviceTopic {
+            tenant: "acme".into(),
+            id: 5,
+        };
+        let topic = template.render(&device)?;
+        assert_eq!(topic, "tenants/acme/devices/5");
+        assert_eq!(template.parse_topic::<DeviceTopic>(&topic)?, device);
+        assert!(
+            template
+                .captures("$share/workers/tenants/acme/devices/5")
+                .is_none()
+        );
+        Ok(())
+    }
+}
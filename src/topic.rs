@@ -0,0 +1,336 @@
+use std::fmt::{self, Display};
+use std::str::FromStr;
+use thiserror::Error;
+
+/// 主题（UTF-8 编码后）的最大长度
+pub const MAX_TOPIC_LEN: usize = 65535;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum TopicError {
+    #[error("topic must not be empty")]
+    Empty,
+    #[error("topic is {0} bytes, longer than {MAX_TOPIC_LEN}")]
+    TooLong(usize),
+    #[error("topic {0:?} must not contain the null character")]
+    ContainsNul(String),
+    #[error("topic name {0} must not contain wildcards")]
+    WildcardInName(String),
+    #[error(
+        "topic name {0} must not start with `$share/`, which is reserved for shared subscriptions"
+    )]
+    Reserved(String),
+    #[error("topic filter {0}: `#` must be the last level and occupy the whole level")]
+    MisplacedMultiLevel(String),
+    #[error("topic filter {0}: `+` must occupy the whole level")]
+    MisplacedSingleLevel(String),
+    #[error("invalid shared subscription {0}, expected $share/{{group}}/{{filter}}")]
+    InvalidShare(String),
+}
+
+fn validate_common(topic: &str) -> Result<(), TopicError> {
+    if topic.is_empty() {
+        return Err(TopicError::Empty);
+    }
+    if topic.len() > MAX_TOPIC_LEN {
+        return Err(TopicError::TooLong(topic.len()));
+    }
+    if topic.contains('\0') {
+        return Err(TopicError::ContainsNul(topic.to_string()));
+    }
+    Ok(())
+}
+
+/// 校验发布使用的主题名：非空、不超过 65535 字节、不含空字符与通配符，
+/// 也不能以共享订阅的 `$share/` 开头。
+/// `$` 开头的其他主题（如 `$aws/things/..`）可以发布，协议只规定它们不被首级通配符匹配；
+/// 按协议空的层级（`a//b`）是合法的
+pub fn validate_topic_name(topic: &str) -> Result<(), TopicError> {
+    validate_common(topic)?;
+    if topic.contains(['+', '#']) {
+        return Err(TopicError::WildcardInName(topic.to_string()));
+    }
+    if topic.starts_with("$share/") {
+        return Err(TopicError::Reserved(topic.to_string()));
+    }
+    Ok(())
+}
+
+/// 校验订阅使用的主题过滤器：`+` 与 `#` 必须占据一整级，`#` 只能位于最后一级，
+/// 共享订阅的格式为 `$share/{group}/{filter}`，group 不能为空且不能包含通配符
+pub fn validate_topic_filter(filter: &str) -> Result<(), TopicError> {
+    validate_common(filter)?;
+    let inner = match filter.strip_prefix("$share/") {
+        Some(rest) => {
+            let invalid = || TopicError::InvalidShare(filter.to_string());
+            let (group, inner) = rest.split_once('/').ok_or_else(invalid)?;
+            if group.is_empty() || group.contains(['+', '#']) || inner.is_empty() {
+                return Err(invalid());
+            }
+            inner
+        }
+        None => filter,
+    };
+
+    let levels: Vec<&str> = inner.split('/').collect();
+    for (i, level) in levels.iter().enumerate() {
+        if level.contains('#') && (*level != "#" || i + 1 != levels.len()) {
+            return Err(TopicError::MisplacedMultiLevel(filter.to_string()));
+        }
+        if level.contains('+') && *level != "+" {
+            return Err(TopicError::MisplacedSingleLevel(filter.to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// 按 MQTT 5 规则匹配主题的过滤器：
+/// - 区分大小写，`+` 匹配恰好一级（可以是空的一级），`#` 匹配其余任意级，也包括父级本身（`a/#` 匹配 `a`）；
+/// - 以 `$` 开头的主题不会被首级的 `+` 或 `#` 匹配，`$SYS/#` 这样显式写出的过滤器不受影响；
+/// - 共享订阅 `$share/{group}/{filter}` 按其中的 filter 匹配
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicFilter {
+    raw: String,
+    // 去掉共享订阅前缀后过滤器在 raw 中的起始位置
+    start: usize,
+}
+
+impl TopicFilter {
+    pub fn new(filter: impl Into<String>) -> Result<Self, TopicError> {
+        let raw = filter.into();
+        validate_topic_filter(&raw)?;
+        let start = match raw.strip_prefix("$share/") {
+            Some(rest) => raw.len() - rest.len() + rest.find('/').unwrap_or_default() + 1,
+            None => 0,
+        };
+        Ok(TopicFilter { raw, start })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.raw
+    }
+
+    /// 实际用于匹配的过滤器，共享订阅时为去掉 `$share/{group}/` 之后的部分
+    pub fn filter(&self) -> &str {
+        &self.raw[self.start..]
+    }
+
+    /// 共享订阅的分组
+    pub fn share_group(&self) -> Option<&str> {
+        let prefix = self.raw[..self.start].strip_prefix("$share/")?;
+        prefix.strip_suffix('/')
+    }
+
+    /// 是否包含 `+` 或 `#`
+    pub fn has_wildcards(&self) -> bool {
+        self.filter()
+            .split('/')
+            .any(|level| level == "+" || level == "#")
+    }
+
+    pub fn matches(&self, topic: &str) -> bool {
+        self.captures(topic).is_some()
+    }
+
+    /// 匹配主题并依次取出各通配符对应的值：`+` 为所在的一级，`#` 为剩余的各级
+    /// （匹配父级时为空字符串）
+    pub fn captures<'t>(&self, topic: &'t str) -> Option<Vec<&'t str>> {
+        let filter = self.filter();
+        if topic.starts_with('$') && filter.starts_with(['+', '#']) {
+            return None;
+        }
+        let mut captures = Vec::new();
+        let mut rest = Some(topic);
+        for level in filter.split('/') {
+            if level == "#" {
+                captures.push(rest.unwrap_or_default());
+                return Some(captures);
+            }
+            let (part, tail) = match rest?.split_once('/') {
+                Some((part, tail)) => (part, Some(tail)),
+                None => (rest?, None),
+            };
+            if level == "+" {
+                captures.push(part);
+            } else if level != part {
+                return None;
+            }
+            rest = tail;
+        }
+        match rest {
+            Some(_) => None,
+            None => Some(captures),
+        }
+    }
+}
+
+impl FromStr for TopicFilter {
+    type Err = TopicError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        TopicFilter::new(s)
+    }
+}
+
+impl Display for TopicFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.raw)
+    }
+}
+
+impl AsRef<str> for TopicFilter {
+    fn as_ref(&self) -> &str {
+        &self.raw
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn topic_name_rules() {
+        for topic in [
+            "a",
+            "a/b",
+            "/a",
+            "a//b",
+            "a/b/",
+            "设备/1",
+            "$aws/things/pump/shadow/update",
+            "$iothub/twin/PATCH/properties/reported/",
+        ] {
+            assert_eq!(validate_topic_name(topic), Ok(()), "topic={topic}");
+        }
+        assert_eq!(validate_topic_name(""), Err(TopicError::Empty));
+        assert!(matches!(
+            validate_topic_name(&"a".repeat(MAX_TOPIC_LEN + 1)),
+            Err(TopicError::TooLong(_))
+        ));
+        assert!(matches!(
+            validate_topic_name("a/\0"),
+            Err(TopicError::ContainsNul(_))
+        ));
+        for topic in ["a/+", "a/#", "a+b"] {
+            assert!(
+                matches!(
+                    validate_topic_name(topic),
+                    Err(TopicError::WildcardInName(_))
+                ),
+                "topic={topic}"
+            );
+        }
+        assert!(matches!(
+            validate_topic_name("$share/g/a"),
+            Err(TopicError::Reserved(_))
+        ));
+    }
+
+    #[test]
+    fn topic_filter_rules() {
+        for filter in [
+            "#",
+            "+",
+            "a/#",
+            "+/+",
+            "a/+/b",
+            "/+",
+            "$SYS/#",
+            "$share/g/a/+",
+            "$share/g/#",
+        ] {
+            assert_eq!(validate_topic_filter(filter), Ok(()), "filter={filter}");
+        }
+        for filter in ["a/#/b", "a#", "a/b#"] {
+            assert!(
+                matches!(
+                    validate_topic_filter(filter),
+                    Err(TopicError::MisplacedMultiLevel(_))
+                ),
+                "filter={filter}"
+            );
+        }
+        for filter in ["a+", "a/+b"] {
+            assert!(
+                matches!(
+                    validate_topic_filter(filter),
+                    Err(TopicError::MisplacedSingleLevel(_))
+                ),
+                "filter={filter}"
+            );
+        }
+        for filter in ["$share/g", "$share//a", "$share/g+/a", "$share/g/"] {
+            assert!(
+                matches!(
+                    validate_topic_filter(filter),
+                    Err(TopicError::InvalidShare(_))
+                ),
+                "filter={filter}"
+            );
+        }
+    }
+
+    fn filter(s: &str) -> TopicFilter {
+        TopicFilter::new(s).unwrap()
+    }
+
+    // MQTT 5.0 规范 4.7 节中的示例
+    #[test]
+    fn spec_conformance() {
+        let cases = [
+            ("sport/tennis/player1/#", "sport/tennis/player1", true),
+            (
+                "sport/tennis/player1/#",
+                "sport/tennis/player1/ranking",
+                true,
+            ),
+            (
+                "sport/tennis/player1/#",
+                "sport/tennis/player1/score/wimbledon",
+                true,
+            ),
+            ("sport/#", "sport", true),
+            ("#", "sport/tennis", true),
+            ("#", "/", true),
+            ("sport/tennis/+", "sport/tennis/player1", true),
+            ("sport/tennis/+", "sport/tennis/player2", true),
+            ("sport/tennis/+", "sport/tennis/player1/ranking", false),
+            ("sport/+", "sport", false),
+            ("sport/+", "sport/", true),
+            ("+/+", "/finance", true),
+            ("/+", "/finance", true),
+            ("+", "/finance", false),
+            ("+/tennis/#", "sport/tennis/player1", true),
+            // 区分大小写
+            ("ACCOUNTS", "Accounts", false),
+            ("sport/tennis", "Sport/tennis", false),
+            ("/finance", "finance", false),
+            // `$` 开头的主题不被首级通配符匹配
+            ("#", "$SYS/broker/clients", false),
+            ("+/monitor/Clients", "$SYS/monitor/Clients", false),
+            ("$SYS/#", "$SYS/monitor/Clients", true),
+            ("$SYS/monitor/+", "$SYS/monitor/Clients", true),
+            // 共享订阅按其中的过滤器匹配
+            ("$share/g/sport/#", "sport/tennis", true),
+            ("$share/g/sport/#", "$share/g/sport/tennis", false),
+        ];
+        for (f, topic, expected) in cases {
+            assert_eq!(filter(f).matches(topic), expected, "{f} ~ {topic}");
+        }
+    }
+
+    #[test]
+    fn filter_captures() {
+        let f = filter("a/+/c/#");
+        assert_eq!(f.captures("a/b/c"), Some(vec!["b", ""]));
+        assert_eq!(f.captures("a/b/c/d/e"), Some(vec!["b", "d/e"]));
+        assert_eq!(f.captures("a/b/x"), None);
+        assert_eq!(filter("a/b").captures("a/b"), Some(vec![]));
+        assert!(!filter("a/b").has_wildcards());
+
+        let f: TopicFilter = "$share/group/+/temp".parse().unwrap();
+        assert_eq!(f.share_group(), Some("group"));
+        assert_eq!(f.filter(), "+/temp");
+        assert_eq!(f.captures("dev1/temp"), Some(vec!["dev1"]));
+        assert!(TopicFilter::new("a/#/b").is_err());
+    }
+}
@@ -0,0 +1,324 @@
+use crate::{TopicError, TopicFilter};
+use std::collections::HashMap;
+
+/// 按主题层级组织的过滤器索引，`+` 与 `#` 是单独的节点。
+///
+/// 查找只沿着与主题各级相同的字面节点以及 `+`、`#` 节点向下，
+/// 耗时与主题层级数和命中的通配分支有关，与过滤器总数无关。
+/// 匹配规则与 [`TopicFilter`] 相同，共享订阅按 `$share/{group}/` 之后的部分索引
+#[derive(Debug, Clone)]
+pub struct TopicTrie<T> {
+    root: Node<T>,
+    len: usize,
+    // 插入序号，匹配结果按插入顺序返回
+    seq: u64,
+}
+
+#[derive(Debug, Clone)]
+struct Node<T> {
+    children: HashMap<String, Node<T>>,
+    // 以该节点结尾的过滤器，`$share/g/a` 与 `a` 落在同一节点
+    entries: Vec<Entry<T>>,
+}
+
+#[derive(Debug, Clone)]
+struct Entry<T> {
+    filter: TopicFilter,
+    seq: u64,
+    value: T,
+}
+
+/// 一次匹配的结果
+#[derive(Debug)]
+pub struct TrieMatch<'a, 't, T> {
+    pub filter: &'a TopicFilter,
+    pub value: &'a T,
+    /// 各通配符对应的值，见 [`TopicFilter::captures`]
+    pub captures: Vec<&'t str>,
+    seq: u64,
+}
+
+impl<T> Default for Node<T> {
+    fn default() -> Self {
+        Node {
+            children: HashMap::new(),
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl<T> Default for TopicTrie<T> {
+    fn default() -> Self {
+        TopicTrie {
+            root: Node::default(),
+            len: 0,
+            seq: 0,
+        }
+    }
+}
+
+impl<T> TopicTrie<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// 添加过滤器，已存在时替换并返回原来的值（保留原来的顺序）
+    pub fn insert(&mut self, filter: &str, value: T) -> Result<Option<T>, TopicError> {
+        let filter = TopicFilter::new(filter)?;
+        let mut node = &mut self.root;
+        for level in filter.filter().split('/') {
+            node = node.children.entry(level.to_string()).or_default();
+        }
+        if let Some(entry) = node.entries.iter_mut().find(|e| e.filter == filter) {
+            return Ok(Some(std::mem::replace(&mut entry.value, value)));
+        }
+        self.seq += 1;
+        node.entries.push(Entry {
+            filter,
+            seq: self.seq,
+            value,
+        });
+        self.len += 1;
+        Ok(None)
+    }
+
+    pub fn get(&self, filter: &str) -> Option<&T> {
+        let mut node = &self.root;
+        for level in inner_filter(filter).split('/') {
+            node = node.children.get(level)?;
+        }
+        node.entries
+            .iter()
+            .find(|e| e.filter.as_str() == filter)
+            .map(|e| &e.value)
+    }
+
+    pub fn get_mut(&mut self, filter: &str) -> Option<&mut T> {
+        let mut node = &mut self.root;
+        for level in inner_filter(filter).split('/') {
+            node = node.children.get_mut(level)?;
+        }
+        node.entries
+            .iter_mut()
+            .find(|e| e.filter.as_str() == filter)
+            .map(|e| &mut e.value)
+    }
+
+    pub fn contains(&self, filter: &str) -> bool {
+        self.get(filter).is_some()
+    }
+
+    pub fn remove(&mut self, filter: &str) -> Option<T> {
+        let levels: Vec<&str> = inner_filter(filter).split('/').collect();
+        let value = Self::remove_at(&mut self.root, &levels, filter)?;
+        self.len -= 1;
+        Some(value)
+    }
+
+    // 删除后清理空的节点
+    fn remove_at(node: &mut Node<T>, levels: &[&str], filter: &str) -> Option<T> {
+        let Some((level, rest)) = levels.split_first() else {
+            let pos = node
+                .entries
+                .iter()
+                .position(|e| e.filter.as_str() == filter)?;
+            return Some(node.entries.remove(pos).value);
+        };
+        let child = node.children.get_mut(*level)?;
+        let value = Self::remove_at(child, rest, filter)?;
+        if child.children.is_empty() && child.entries.is_empty() {
+            node.children.remove(*level);
+        }
+        Some(value)
+    }
+
+    /// 所有过滤器，按插入顺序
+    pub fn iter(&self) -> impl Iterator<Item = (&TopicFilter, &T)> {
+        let mut entries = Vec::with_capacity(self.len);
+        let mut stack = vec![&self.root];
+        while let Some(node) = stack.pop() {
+            entries.extend(node.entries.iter());
+            stack.extend(node.children.values());
+        }
+        entries.sort_by_key(|e| e.seq);
+        entries.into_iter().map(|e| (&e.filter, &e.value))
+    }
+
+    /// 是否有任意过滤器匹配该主题
+    pub fn matches(&self, topic: &str) -> bool {
+        let mut found = false;
+        self.walk(topic, &mut |_| {
+            found = true;
+            false
+        });
+        found
+    }
+
+    /// 匹配该主题的所有过滤器，按插入顺序返回
+    pub fn matching<'a, 't>(&'a self, topic: &'t str) -> Vec<TrieMatch<'a, 't, T>> {
+        let mut matched = Vec::new();
+        self.walk(topic, &mut |entry| {
+            matched.push(TrieMatch {
+                filter: &entry.filter,
+                value: &entry.value,
+                captures: Vec::new(),
+                seq: entry.seq,
+            });
+            true
+        });
+        matched.sort_by_key(|m| m.seq);
+        for m in &mut matched {
+            // 命中的过滤器一定匹配，这里只是取出通配符的值
+            m.captures = m.filter.captures(topic).unwrap_or_default();
+        }
+        matched
+    }
+
+    // 对每个命中的过滤器调用 f，f 返回 false 时停止
+    fn walk<'a>(&'a self, topic: &str, f: &mut dyn FnMut(&'a Entry<T>) -> bool) {
+        let levels: Vec<&str> = topic.split('/').collect();
+        // `$` 开头的主题不被首级通配符匹配
+        let wildcards = !topic.starts_with('$');
+        Self::walk_at(&self.root, &levels, wildcards, f);
+    }
+
+    fn walk_at<'a>(
+        node: &'a Node<T>,
+        levels: &[&str],
+        wildcards: bool,
+        f: &mut dyn FnMut(&'a Entry<T>) -> bool,
+    ) -> bool {
+        // `#` 匹配剩余的各级，也包括父级本身
+        if wildcards && let Some(hash) = node.children.get("#") {
+            for entry in &hash.entries {
+                if !f(entry) {
+                    return false;
+                }
+            }
+        }
+        let Some((level, rest)) = levels.split_first() else {
+            return node.entries.iter().all(f);
+        };
+        if *level != "+"
+            && *level != "#"
+            && let Some(child) = node.children.get(*level)
+            && !Self::walk_at(child, rest, true, f)
+        {
+            return false;
+        }
+        if wildcards && let Some(child) = node.children.get("+") {
+            return Self::walk_at(child, rest, true, f);
+        }
+        true
+    }
+}
+
+fn inner_filter(filter: &str) -> &str {
+    filter
+        .strip_prefix("$share/")
+        .and_then(|rest| rest.split_once('/'))
+        .map_or(filter, |(_, inner)| inner)
+}
+
+impl<T> IntoIterator for TopicTrie<T> {
+    type Item = (TopicFilter, T);
+    type IntoIter = std::vec::IntoIter<(TopicFilter, T)>;
+
+    /// 按插入顺序
+    fn into_iter(self) -> Self::IntoIter {
+        let mut entries = Vec::with_capacity(self.len);
+        let mut stack = vec![self.root];
+        while let Some(node) = stack.pop() {
+            entries.extend(node.entries);
+            stack.extend(node.children.into_values());
+        }
+        entries.sort_by_key(|e| e.seq);
+        let entries: Vec<_> = entries.into_iter().map(|e| (e.filter, e.value)).collect();
+        entries.into_iter()
+    }
+}
+
+impl<F: AsRef<str>, T> FromIterator<(F, T)> for TopicTrie<T> {
+    /// 忽略不合法的过滤器
+    fn from_iter<I: IntoIterator<Item = (F, T)>>(iter: I) -> Self {
+        let mut trie = TopicTrie::new();
+        for (filter, value) in iter {
+            let filter = filter.as_ref();
+            if let Err(e) = trie.insert(filter, value) {
+                log::warn!("skip topic filter {}: {}", filter, e);
+            }
+        }
+        trie
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn matched(trie: &TopicTrie<u32>, topic: &str) -> Vec<u32> {
+        trie.matching(topic).iter().map(|m| *m.value).collect()
+    }
+
+    #[test]
+    fn overlapping_wildcards() {
+        let mut trie = TopicTrie::new();
+        for (i, filter) in [
+            "a/+/c",
+            "a/b/+",
+            "#",
+            "a/#",
+            "a/b/c",
+            "+/+/+",
+            "$share/g/a/b/c",
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            assert_eq!(trie.insert(filter, i as u32), Ok(None));
+        }
+        assert_eq!(trie.len(), 7);
+        assert_eq!(matched(&trie, "a/b/c"), [0, 1, 2, 3, 4, 5, 6]);
+        assert_eq!(matched(&trie, "a/x/c"), [0, 2, 3, 5]);
+        assert_eq!(matched(&trie, "a"), [2, 3]);
+        assert_eq!(matched(&trie, "b"), [2]);
+        assert_eq!(matched(&trie, "$SYS/b/c"), Vec::<u32>::new());
+
+        let m = &trie.matching("a/b/c")[0];
+        assert_eq!(m.filter.as_str(), "a/+/c");
+        assert_eq!(m.captures, ["b"]);
+
+        // 替换保留原来的顺序
+        assert_eq!(trie.insert("a/+/c", 10), Ok(Some(0)));
+        assert_eq!(matched(&trie, "a/x/c"), [10, 2, 3, 5]);
+
+        assert_eq!(trie.remove("a/#"), Some(3));
+        assert_eq!(trie.remove("a/#"), None);
+        assert_eq!(trie.remove("$share/g/a/b/c"), Some(6));
+        assert_eq!(trie.get("a/b/c"), Some(&4));
+        assert_eq!(matched(&trie, "a/b/c"), [10, 1, 2, 4, 5]);
+        assert_eq!(
+            trie.iter().map(|(f, _)| f.as_str()).collect::<Vec<_>>(),
+            ["a/+/c", "a/b/+", "#", "a/b/c", "+/+/+"]
+        );
+        assert!(trie.insert("a/#/b", 0).is_err());
+    }
+
+    #[test]
+    fn many_filters() {
+        let trie: TopicTrie<u32> = (0..5000)
+            .map(|i| (format!("tenants/{}/devices/{}/+", i % 50, i), i))
+            .collect();
+        assert_eq!(trie.len(), 5000);
+        assert_eq!(matched(&trie, "tenants/7/devices/1207/temp"), [1207]);
+        assert!(!trie.matches("tenants/7/devices/1208/temp"));
+    }
+}
@@ -1,4 +1,5 @@
 #![allow(non_upper_case_globals)]
+use crate::TopicFilter;
 use bytes::Bytes;
 pub use rumqttc::v5::mqttbytes::QoS;
 pub use rumqttc::v5::mqttbytes::v5::Publish as Message;
@@ -117,6 +118,19 @@ where
     let q = rumqttc::v5::mqttbytes::qos(v).unwrap_or(QoS::AtMostOnce);
     Ok(q)
 }
+/// 把 `#` 匹配到的剩余主题按级拆分，空字符串对应空列表：
+/// `#[serde(deserialize_with = "rmqttc::deserialize_segments")] rest: Vec<String>`
+pub fn deserialize_segments<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let rest = String::deserialize(deserializer)?;
+    if rest.is_empty() {
+        return Ok(Vec::new());
+    }
+    Ok(rest.split('/').map(str::to_string).collect())
+}
+
 pub fn qos_to_u8(qos: &QoS) -> u8 {
     match qos {
         QoS::AtMostOnce => 0,
@@ -130,87 +144,30 @@ pub fn json_value_into_bytes(v: Value) -> Bytes {
     Bytes::from(d)
 }
 
+#[deprecated(note = "use `TopicTemplate::render`")]
 pub fn to_topic(topic: &str, skuid: &str, uuid: &str) -> String {
     topic.replace("{skuid}", skuid).replace("{uuid}", uuid)
 }
 
 // /aam/sub/request/2928/10002 是否符合 /aam/sub/request/+/+
+#[deprecated(note = "use `TopicFilter::matches`")]
 pub fn topic_match_one(topic: &str, topic_filter: &str) -> bool {
-    if topic.to_lowercase() == topic_filter.to_lowercase() {
-        return true;
-    }
-
-    let topic_parts: Vec<&str> = topic.split('/').collect();
-    let pattern_parts: Vec<&str> = topic_filter.split('/').collect();
-    if topic_parts.len() != pattern_parts.len() {
-        return false;
-    }
-
-    for (i, pattern_part) in pattern_parts.iter().enumerate() {
-        if *pattern_part != "+" && *pattern_part != topic_parts[i] {
-            return false;
-        }
-    }
-    true
+    TopicFilter::new(topic_filter).is_ok_and(|f| f.matches(topic))
 }
 
 // /aam/sub/request/2928/10002 是否符合 /aam/sub/request/+/+
 // 提取 + + 里的值
+#[deprecated(note = "use `TopicFilter::captures`")]
 pub fn topic_get_match_one(topic: &str, topic_filter: &str) -> Option<Vec<String>> {
-    let topic_parts: Vec<&str> = topic.split('/').collect();
-    let pattern_parts: Vec<&str> = topic_filter.split('/').collect();
-    if topic_parts.len() != pattern_parts.len() {
-        return None;
-    }
-
-    let mut values = Vec::new();
-    for (i, pattern_part) in pattern_parts.iter().enumerate() {
-        if *pattern_part == "+" {
-            values.push(topic_parts[i].to_string());
-        } else if *pattern_part != topic_parts[i] {
-            return None; // 静态部分不匹配
-        }
-    }
-    Some(values)
+    let filter = TopicFilter::new(topic_filter).ok()?;
+    let values = filter.captures(topic)?;
+    Some(values.into_iter().map(str::to_string).collect())
 }
 
 //test/topic/1/21/2232  能配符配置 test/topic/#
+#[deprecated(note = "use `TopicFilter::matches`")]
 pub fn topic_match_all(topic: &str, filter: &str) -> bool {
-    if topic.to_lowercase() == filter.to_lowercase() {
-        return true;
-    }
-
-    let topic_parts: Vec<&str> = topic.split('/').collect();
-    let filter_parts: Vec<&str> = filter.split('/').collect();
-
-    // 检查是否存在 # 通配符
-    if let Some(pos) = filter_parts.iter().position(|&x| x == "#") {
-        // 检查静态前缀是否匹配
-        if topic_parts.len() < pos {
-            return false;
-        }
-
-        for i in 0..pos {
-            if filter_parts[i] != "+" && filter_parts[i] != topic_parts[i] {
-                return false;
-            }
-        }
-
-        true
-    } else {
-        // 没有 # 通配符时需要完全匹配
-        if topic_parts.len() != filter_parts.len() {
-            return false;
-        }
-
-        for (i, &filter_part) in filter_parts.iter().enumerate() {
-            if filter_part != "+" && filter_part != topic_parts[i] {
-                return false;
-            }
-        }
-
-        true
-    }
+    TopicFilter::new(filter).is_ok_and(|f| f.matches(topic))
 }
 
 // bytes to string
@@ -219,6 +176,7 @@ pub fn bytes_to_string(b: &Bytes) -> Option<String> {
 }
 
 #[cfg(test)]
+#[allow(deprecated)]
 mod test {
     use super::*;
     #[test]